@@ -0,0 +1,396 @@
+use super::error::{self, ErrorKind, EvalError};
+use super::types::{Function, Primitive};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// A Rust type that can be read from a chibi value
+pub trait FromValue: Sized {
+    fn from_value(value: Primitive) -> Result<Self, EvalError>;
+}
+
+/// A Rust type that can be handed to chibi code
+///
+/// Conversion can fail, for example with `ErrorKind::Overflow` for integers that do not fit in a
+/// chibi integer. Functions wrapped by `function` can also return `Result<T, EvalError>` to report
+/// their own errors.
+pub trait IntoValue {
+    fn into_value(self) -> Result<Primitive, EvalError>;
+}
+
+fn expected<T>(description: &str, value: &Primitive) -> Result<T, EvalError> {
+    Err(EvalError::type_error(format!("expected {} but found {:?}", description, value)))
+}
+
+impl FromValue for Primitive {
+    fn from_value(value: Primitive) -> Result<Primitive, EvalError> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for Primitive {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Result<T, EvalError> {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        self?.into_value()
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(Primitive::Nil)
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($integer:ty),*) => {
+        $(
+            impl FromValue for $integer {
+                fn from_value(value: Primitive) -> Result<$integer, EvalError> {
+                    match value {
+                        Primitive::Integer(integer) => <$integer>::try_from(integer).or_else(|_| {
+                            expected(concat!("an integer in the range of ", stringify!($integer)), &value)
+                        }),
+                        _ => expected("an integer", &value)
+                    }
+                }
+            }
+
+            impl IntoValue for $integer {
+                fn into_value(self) -> Result<Primitive, EvalError> {
+                    match i32::try_from(self) {
+                        Ok(integer) => Ok(Primitive::Integer(integer)),
+                        Err(_) => Err(EvalError::new(ErrorKind::Overflow, format!("{} does not fit in an integer", self)))
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromValue for f64 {
+    /// Integers are accepted too, since every chibi integer is exactly representable as a float
+    fn from_value(value: Primitive) -> Result<f64, EvalError> {
+        match value {
+            Primitive::Float(float) => Ok(float),
+            Primitive::Integer(integer) => Ok(f64::from(integer)),
+            _ => expected("a number", &value)
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(Primitive::Float(self))
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Primitive) -> Result<bool, EvalError> {
+        match value {
+            Primitive::Bool(boolean) => Ok(boolean),
+            _ => expected("a boolean", &value)
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(Primitive::Bool(self))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Primitive) -> Result<String, EvalError> {
+        match &value {
+            Primitive::String(string) => Ok(string.clone()),
+            _ => expected("a string", &value)
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(Primitive::String(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        Ok(Primitive::String(self.to_string()))
+    }
+}
+
+/// The elements of a proper list
+fn elements(value: Primitive) -> Result<Vec<Primitive>, EvalError> {
+    let mut items = vec![];
+    let mut rest = &value;
+
+    while let Primitive::Pair(car, cdr) = rest {
+        items.push(car.as_ref().clone());
+        rest = cdr;
+    }
+
+    match rest {
+        Primitive::Nil => Ok(items),
+        _ => expected("a list", &value)
+    }
+}
+
+/// Vectors are proper lists
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Primitive) -> Result<Vec<T>, EvalError> {
+        elements(value)?.into_iter().map(T::from_value).collect()
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        let items = self.into_iter().map(T::into_value).collect::<Result<Vec<_>, _>>()?;
+        Ok(Primitive::list(items))
+    }
+}
+
+/// `None` is `false`, the value Scheme procedures return when they have nothing to return
+///
+/// As `false` always reads as `None`, an `Option<bool>` is never `Some(false)`. Take a `bool` or
+/// a `Primitive` where `false` needs to be told apart from a missing value.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Primitive) -> Result<Option<T>, EvalError> {
+        match value {
+            Primitive::Bool(false) => Ok(None),
+            value => T::from_value(value).map(Some)
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        match self {
+            Some(value) => value.into_value(),
+            None => Ok(Primitive::Bool(false))
+        }
+    }
+}
+
+/// Maps are association lists, `((key . value) ...)`, where later entries win
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for HashMap<K, V> {
+    fn from_value(value: Primitive) -> Result<HashMap<K, V>, EvalError> {
+        let mut map = HashMap::new();
+
+        for entry in elements(value)? {
+            match &entry {
+                Primitive::Pair(key, value) => {
+                    map.insert(K::from_value(key.as_ref().clone())?, V::from_value(value.as_ref().clone())?);
+                },
+                _ => return expected("a (key . value) pair", &entry)
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+impl<K: IntoValue, V: IntoValue> IntoValue for HashMap<K, V> {
+    fn into_value(self) -> Result<Primitive, EvalError> {
+        let mut entries = vec![];
+
+        for (key, value) in self {
+            entries.push(Primitive::cons(key.into_value()?, value.into_value()?));
+        }
+
+        Ok(Primitive::list(entries))
+    }
+}
+
+macro_rules! count {
+    () => { 0 };
+    ($first:ident $($rest:ident)*) => { 1 + count!($($rest)*) };
+}
+
+/// Tuples are lists of the same length
+macro_rules! tuple_conversions {
+    ($($element:ident),+) => {
+        impl<$($element: FromValue),+> FromValue for ($($element,)+) {
+            #[allow(non_snake_case)]
+            fn from_value(value: Primitive) -> Result<($($element,)+), EvalError> {
+                let items = elements(value.clone())?;
+
+                if items.len() != count!($($element)+) {
+                    return expected(&format!("a list of {} elements", count!($($element)+)), &value);
+                }
+
+                let mut items = items.into_iter();
+                $(let $element = $element::from_value(items.next().unwrap())?;)+
+                Ok(($($element,)+))
+            }
+        }
+
+        impl<$($element: IntoValue),+> IntoValue for ($($element,)+) {
+            #[allow(non_snake_case)]
+            fn into_value(self) -> Result<Primitive, EvalError> {
+                let ($($element,)+) = self;
+                Ok(Primitive::list(vec![$($element.into_value()?),+]))
+            }
+        }
+    };
+}
+
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+tuple_conversions!(A, B, C, D, E);
+
+/// A Rust function whose arguments and result convert to and from chibi values
+///
+/// Implemented for closures and functions of up to six arguments. `Args` is the tuple of argument
+/// types, which keeps the implementations for different arities apart.
+pub trait TypedFunction<Args> {
+    fn arity(&self) -> usize;
+    /// Convert `arguments`, which must be `arity` values, and call the function
+    fn invoke(&self, name: &str, arguments: Vec<Primitive>) -> Result<Primitive, EvalError>;
+}
+
+macro_rules! typed_function {
+    ($($argument:ident),*) => {
+        impl<Func, R, $($argument),*> TypedFunction<($($argument,)*)> for Func
+        where Func: Fn($($argument),*) -> R, R: IntoValue, $($argument: FromValue),* {
+            fn arity(&self) -> usize {
+                count!($($argument)*)
+            }
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn invoke(&self, name: &str, arguments: Vec<Primitive>) -> Result<Primitive, EvalError> {
+                let mut arguments = arguments.into_iter().enumerate();
+                $(
+                    let (index, value) = arguments.next().unwrap();
+                    let $argument = $argument::from_value(value).map_err(|error| {
+                        EvalError::new(error.kind, format!("{} argument {}: {}", name, index + 1, error.message))
+                    })?;
+                )*
+                self($($argument),*).into_value()
+            }
+        }
+    };
+}
+
+typed_function!();
+typed_function!(A);
+typed_function!(A, B);
+typed_function!(A, B, C);
+typed_function!(A, B, C, D);
+typed_function!(A, B, C, D, E);
+typed_function!(A, B, C, D, E, F);
+
+/// Wrap a typed Rust function as a chibi procedure called `name`
+///
+/// The procedure checks the number of arguments and converts each of them, failing with an arity
+/// or type error naming `name` when they do not fit.
+pub fn function<Args, F>(name: &str, function: F) -> Function
+where F: TypedFunction<Args> + 'static {
+    let name = name.to_string();
+
+    Rc::new(move |_, arguments| {
+        let arity = function.arity();
+
+        if arguments.len() != arity {
+            return Err(EvalError::arity_error(format!("{} expects {} but got {}", name, error::arguments(arity), arguments.len())));
+        }
+
+        function.invoke(&name, arguments)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::error::ErrorKind;
+    use super::super::interpreter::interpret;
+    use super::super::parser::parse;
+    use super::super::env;
+
+    fn value<T: IntoValue>(value: T) -> Primitive {
+        value.into_value().unwrap()
+    }
+
+    #[test]
+    fn converts_scalars() {
+        assert_eq!(i64::from_value(Primitive::Integer(-3)), Ok(-3));
+        assert_eq!(f64::from_value(Primitive::Integer(2)), Ok(2.0));
+        assert_eq!(bool::from_value(Primitive::Bool(true)), Ok(true));
+        assert_eq!(String::from_value(Primitive::String(String::from("a"))), Ok(String::from("a")));
+        assert_eq!(value(7u8), Primitive::Integer(7));
+        assert_eq!(value("a"), Primitive::String(String::from("a")));
+
+        assert_eq!(u8::from_value(Primitive::Integer(-1)).unwrap_err().kind, ErrorKind::TypeError);
+        assert_eq!(i32::from_value(Primitive::Float(1.0)).unwrap_err().kind, ErrorKind::TypeError);
+        assert_eq!(i64::MAX.into_value().unwrap_err().kind, ErrorKind::Overflow);
+    }
+
+    #[test]
+    fn out_of_range_integers_overflow() {
+        assert_eq!(value(i64::from(i32::MAX)), Primitive::Integer(i32::MAX));
+        assert_eq!((i64::from(i32::MAX) + 1).into_value().unwrap_err().kind, ErrorKind::Overflow);
+        assert_eq!((i64::from(i32::MIN) - 1).into_value().unwrap_err().kind, ErrorKind::Overflow);
+        assert_eq!(u64::MAX.into_value().unwrap_err().kind, ErrorKind::Overflow);
+
+        let scope = env::standard_env();
+        scope.define("big".to_string(), Primitive::Function(function("big", || i64::MAX)));
+        let error = interpret(parse("(big)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Overflow);
+    }
+
+    #[test]
+    fn converts_collections() {
+        let list = Primitive::list(vec![Primitive::Integer(1), Primitive::Integer(2)]);
+        assert_eq!(Vec::<i32>::from_value(list.clone()), Ok(vec![1, 2]));
+        assert_eq!(value(vec![1, 2]), list);
+        assert_eq!(<(i32, f64)>::from_value(list.clone()), Ok((1, 2.0)));
+        assert_eq!(value((1, "a")), Primitive::list(vec![Primitive::Integer(1), Primitive::String(String::from("a"))]));
+        assert!(<(i32,)>::from_value(list).is_err());
+
+        assert_eq!(Option::<i32>::from_value(Primitive::Bool(false)), Ok(None));
+        assert_eq!(Option::<i32>::from_value(Primitive::Integer(1)), Ok(Some(1)));
+        assert_eq!(Option::<bool>::from_value(Primitive::Bool(false)), Ok(None));
+        assert_eq!(value(None::<i32>), Primitive::Bool(false));
+
+        let mut map = HashMap::new();
+        map.insert(String::from("a"), 1);
+        let alist = Primitive::list(vec![Primitive::cons(Primitive::String(String::from("a")), Primitive::Integer(1))]);
+        assert_eq!(value(map.clone()), alist);
+        assert_eq!(HashMap::<String, i32>::from_value(alist), Ok(map));
+    }
+
+    #[test]
+    fn wraps_typed_functions() {
+        let scope = env::standard_env();
+        scope.define(String::from("describe"), Primitive::Function(function("describe", |count: i64, size: f64| {
+            format!("{} x {}", count, size)
+        })));
+        scope.define(String::from("checked-half"), Primitive::Function(function("checked-half", |n: i32| {
+            if n % 2 == 0 { Ok(n / 2) } else { Err(EvalError::type_error("odd")) }
+        })));
+
+        let result = interpret(parse("(describe 3 1.5)").unwrap(), &scope, true).unwrap();
+        assert_eq!(result, Primitive::String(String::from("3 x 1.5")));
+        assert_eq!(interpret(parse("(checked-half 4)").unwrap(), &scope, true), Ok(Primitive::Integer(2)));
+
+        let error = interpret(parse("(describe 3)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ArityError);
+        assert_eq!(error.message, "describe expects 2 arguments but got 1");
+
+        let error = interpret(parse("(describe \"3\" 1.5)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        assert_eq!(error.message, "describe argument 1: expected an integer but found String(3)");
+
+        assert_eq!(interpret(parse("(checked-half 3)").unwrap(), &scope, true).unwrap_err().message, "odd");
+    }
+}
@@ -1,215 +1,521 @@
 use super::types::*;
+use super::error::{self, EvalError};
+use super::convert::{self, FromValue};
+use super::interpreter::{self, Context};
+use super::library::Libraries;
+use std::cell::RefCell;
 use std::collections::HashMap;
-
-#[derive(Debug, Clone)]
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+/// A lexical environment
+///
+/// Scopes are cheap handles onto a chain of reference-counted frames. Cloning a scope shares its
+/// frames, so a binding made through one handle is visible through every other handle (and every
+/// closure) that shares the frame.
+///
+/// Builtins are bound in the outermost frame, below the global one, so programs can shadow them
+/// like any other binding. Every scope derived from the same `standard_env` also shares its
+/// library registry, and the heap its frames are tracked in.
+#[derive(Clone)]
 pub struct Scope {
-    pub native_procedures: HashMap<String, Primitive>,
-    pub variables: HashMap<String, Primitive>
+    libraries: Rc<RefCell<Libraries>>,
+    truthiness: Truthiness,
+    heap: Rc<RefCell<Heap>>,
+    frame: Rc<RefCell<Frame>>
 }
 
-pub fn standard_env() -> Scope {
-    let mut native_procedures: HashMap<String, Primitive> = HashMap::new();
-    native_procedures.insert("+".to_string(), Primitive::Function(addition));
-    native_procedures.insert("-".to_string(), Primitive::Function(subtract));
-    native_procedures.insert("*".to_string(), Primitive::Function(multiply));
-    native_procedures.insert("/".to_string(), Primitive::Function(divide));
-    native_procedures.insert(">".to_string(), Primitive::Function(greater_than));
-    native_procedures.insert("<".to_string(), Primitive::Function(less_than));
-    native_procedures.insert("=".to_string(), Primitive::Function(equals));
-    native_procedures.insert("not".to_string(), Primitive::Function(not));
-
-    let variables: HashMap<String, Primitive> = HashMap::new();
+/// Which values conditionals such as `if`, `cond`, `and`, `or` and `not` treat as false
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truthiness {
+    /// Only `false` is false, as in Scheme
+    Scheme,
+    /// `false`, `0` and the empty list are false, as in earlier versions of chibi
+    Legacy
+}
 
-    Scope { native_procedures, variables }
+impl Truthiness {
+    pub fn is_true(self, value: &Primitive) -> bool {
+        match self {
+            Truthiness::Scheme => value != &Primitive::Bool(false),
+            Truthiness::Legacy => {
+                value != &Primitive::Bool(false) &&
+                value != &Primitive::Integer(0) &&
+                value != &Primitive::Nil
+            }
+        }
+    }
 }
 
-fn falsy(v: &Primitive) -> bool {
-    v == &Primitive::Bool(false) ||
-    v == &Primitive::Integer(0) ||
-    v == &Primitive::Nil
+struct Frame {
+    variables: HashMap<String, Primitive>,
+    parent: Option<Scope>
 }
 
-fn truthy(v: &Primitive) -> bool {
-    !falsy(v)
+/// Number of tracked frames below which the heap is never collected
+const COLLECTION_THRESHOLD: usize = 1024;
+
+/// Every frame created under one `standard_env`
+///
+/// A closure holds on to the frame it was created in, and that frame usually binds the closure (a
+/// procedure with internal definitions, a named let, a letrec), so the frame and the closure keep
+/// each other alive and reference counting alone never frees them. Tracking the frames lets
+/// `Scope::collect_garbage` find such cycles and break them.
+struct Heap {
+    frames: Vec<Weak<RefCell<Frame>>>,
+    threshold: usize
 }
 
-fn not(list: Vec<Primitive>) -> Primitive {
-    if truthy(&list[0]) {
-        return Primitive::Bool(false);
-    } else {
-        return Primitive::Bool(true);
+impl Scope {
+    /// Create a child scope whose lookups fall back to this one
+    ///
+    /// Collects garbage once enough frames were created since the last collection.
+    pub(crate) fn extend(&self) -> Scope {
+        let frame = Frame { variables: HashMap::new(), parent: Some(self.clone()) };
+
+        let scope = Scope {
+            libraries: Rc::clone(&self.libraries),
+            truthiness: self.truthiness,
+            heap: Rc::clone(&self.heap),
+            frame: Rc::new(RefCell::new(frame))
+        };
+
+        let full = {
+            let mut heap = self.heap.borrow_mut();
+            heap.frames.push(Rc::downgrade(&scope.frame));
+            heap.frames.len() >= heap.threshold
+        };
+
+        if full {
+            self.collect_garbage();
+        }
+
+        scope
     }
-}
 
-fn greater_than(list: Vec<Primitive>) -> Primitive {
-    let left = &list[0];
-    let right = &list[1];
+    /// Free the frames that are only kept alive by reference cycles
+    ///
+    /// There is no way to enumerate the frames referenced from Rust, so instead every reference a
+    /// tracked frame holds to another tracked frame, through its parent or a procedure bound in it,
+    /// is subtracted from the target's reference count. Frames with references left over are
+    /// referenced from elsewhere. Everything they reach is alive, and the remaining frames are
+    /// emptied, which breaks their cycles. References held through shared values are not followed,
+    /// so they count as outside references and keep their frames alive.
+    pub(crate) fn collect_garbage(&self) {
+        let frames: Vec<Rc<RefCell<Frame>>> = self.heap.borrow().frames.iter().filter_map(Weak::upgrade).collect();
+        let index: HashMap<*const RefCell<Frame>, usize> = frames.iter().enumerate()
+            .map(|(index, frame)| (Rc::as_ptr(frame), index))
+            .collect();
+
+        // less the reference held by `frames`
+        let mut outside: Vec<usize> = frames.iter().map(|frame| Rc::strong_count(frame) - 1).collect();
+        let mut edges: Vec<Vec<usize>> = vec![vec![]; frames.len()];
+        let mut alive = vec![false; frames.len()];
+        let mut work = frames.len();
+
+        for (from, frame) in frames.iter().enumerate() {
+            let frame = match frame.try_borrow() {
+                Ok(frame) => frame,
+                // a frame being read or written is in use
+                Err(_) => {
+                    alive[from] = true;
+                    continue;
+                }
+            };
+
+            let mut targets = vec![];
+
+            if let Some(parent) = &frame.parent {
+                targets.push(Rc::as_ptr(&parent.frame));
+            }
+            for value in frame.variables.values() {
+                work += referenced_frames(value, &mut targets);
+            }
+
+            for target in targets {
+                if let Some(&to) = index.get(&target) {
+                    outside[to] -= 1;
+                    edges[from].push(to);
+                }
+            }
+        }
 
-    if let Primitive::Integer(a1) = left {
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(f64::from(*a1) == *a2)
+        let mut pending: Vec<usize> = (0..frames.len()).filter(|&index| alive[index] || outside[index] > 0).collect();
+
+        for &index in &pending {
+            alive[index] = true;
         }
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(a1 > a2);
+
+        while let Some(from) = pending.pop() {
+            for &to in &edges[from] {
+                if !alive[to] {
+                    alive[to] = true;
+                    pending.push(to);
+                }
+            }
         }
-    }
 
-    if let Primitive::Float(a1) = left {
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(*a1 == f64::from(*a2))
+        let mut garbage = vec![];
+
+        for (frame, alive) in frames.iter().zip(&alive) {
+            if !alive {
+                let mut frame = frame.borrow_mut();
+                garbage.push((mem::take(&mut frame.variables), frame.parent.take()));
+            }
         }
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(a1 > a2);
+
+        {
+            let mut heap = self.heap.borrow_mut();
+            heap.frames = frames.iter().zip(&alive).filter(|(_, alive)| **alive).map(|(frame, _)| Rc::downgrade(frame)).collect();
+            // wait for at least as many new frames as this collection looked at values, so the
+            // time spent collecting stays proportional to the number of frames created
+            let live = heap.frames.len();
+            heap.threshold = COLLECTION_THRESHOLD.max(2 * live).max(live + work);
         }
+
+        // only now, with no frame or the heap borrowed, free the values the cycles held
+        drop(garbage);
     }
 
-    panic!("cannot compare {:?} and {:?}", left, right);
-}
+    /// Handles onto every frame tracked so far, which report whether the frame was freed
+    #[cfg(test)]
+    pub fn frame_probes(&self) -> Vec<Weak<dyn std::any::Any>> {
+        self.heap.borrow().frames.iter().map(|frame| frame.clone() as Weak<dyn std::any::Any>).collect()
+    }
 
-fn equals(list: Vec<Primitive>) -> Primitive {
-    let left = &list[0];
-    let right = &list[1];
+    /// The library registry shared by every scope derived from the same `standard_env`
+    pub(crate) fn libraries(&self) -> &RefCell<Libraries> {
+        &self.libraries
+    }
 
-    if let Primitive::Integer(a1) = left {
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(f64::from(*a1) == *a2)
+    /// Which values conditionals evaluated in this scope treat as false
+    pub(crate) fn truthiness(&self) -> Truthiness {
+        self.truthiness
+    }
+
+    /// Create an empty top-level scope that shares builtins and libraries with this one
+    pub(crate) fn fresh(&self) -> Scope {
+        let parent = self.frame.borrow().parent.clone();
+
+        match parent {
+            Some(parent) => parent.fresh(),
+            None => self.extend()
         }
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(a1 == a2);
+    }
+
+    /// Look up a variable, walking outward through the enclosing frames
+    pub fn get(&self, name: &str) -> Option<Primitive> {
+        let frame = self.frame.borrow();
+
+        match frame.variables.get(name) {
+            Some(value) => Some(value.clone()),
+            None => frame.parent.as_ref().and_then(|parent| parent.get(name))
         }
     }
 
-    if let Primitive::Float(a1) = left {
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(*a1 == f64::from(*a2))
+    /// Bind a variable in the current frame, shadowing any outer binding
+    pub fn define(&self, name: String, value: Primitive) {
+        self.frame.borrow_mut().variables.insert(name, value);
+    }
+
+    /// Change the value of an existing variable in the nearest frame that binds it
+    ///
+    /// Returns false, leaving every frame untouched, when no frame binds `name`.
+    pub fn set(&self, name: &str, value: Primitive) -> bool {
+        let mut frame = self.frame.borrow_mut();
+
+        match frame.variables.get_mut(name) {
+            Some(binding) => {
+                *binding = value;
+                true
+            },
+            None => match &frame.parent {
+                Some(parent) => parent.set(name, value),
+                None => false
+            }
         }
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(a1 == a2);
+    }
+
+    /// Whether `function` is what `name` is bound to in the outermost frame, the builtins
+    pub(crate) fn is_builtin(&self, function: &Function, name: &str) -> bool {
+        let mut frame = Rc::clone(&self.frame);
+
+        loop {
+            let parent = match &frame.borrow().parent {
+                Some(parent) => Rc::clone(&parent.frame),
+                None => break
+            };
+            frame = parent;
         }
+
+        let frame = frame.borrow();
+        matches!(frame.variables.get(name), Some(Primitive::Function(builtin)) if Rc::ptr_eq(builtin, function))
+    }
+
+    /// Bind `name` in the current frame to a procedure implemented by `function`
+    ///
+    /// The closure can capture whatever state it needs. It is called with the context of each call
+    /// and the evaluated arguments, and can fail with any `EvalError`.
+    pub fn define_native<F>(&self, name: &str, function: F)
+    where F: Fn(&Context, Vec<Primitive>) -> Result<Primitive, EvalError> + 'static {
+        self.define(name.to_string(), Primitive::Function(Rc::new(function)));
     }
 
-    panic!("cannot compare {:?} and {:?}", left, right);
+    /// The bindings made in the current frame, ignoring enclosing frames
+    pub fn local_bindings(&self) -> Vec<(String, Primitive)> {
+        let frame = self.frame.borrow();
+        frame.variables.iter().map(|(name, value)| (name.clone(), value.clone())).collect()
+    }
 }
 
-fn less_than(list: Vec<Primitive>) -> Primitive {
-    let left = &list[0];
-    let right = &list[1];
+impl fmt::Debug for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame.borrow();
 
-    if let Primitive::Integer(a1) = left {
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(a1 < a2);
-        }
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(f64::from(*a1) < *a2);
-        }
+        f.debug_struct("Scope")
+            .field("variables", &frame.variables)
+            .field("parent", &frame.parent)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub fn standard_env() -> Scope {
+    standard_env_with(Truthiness::Scheme)
+}
+
+/// A global scope whose conditionals follow `truthiness`
+pub fn standard_env_with(truthiness: Truthiness) -> Scope {
+    let negate: Native = match truthiness {
+        Truthiness::Scheme => not,
+        Truthiness::Legacy => legacy_not
+    };
+
+    let builtins: [(&str, Native); 12] = [
+        ("+", addition),
+        ("-", subtract),
+        ("*", multiply),
+        ("/", divide),
+        ("not", negate),
+        ("cons", cons),
+        ("car", car),
+        ("cdr", cdr),
+        ("list", list),
+        ("null?", is_null),
+        ("pair?", is_pair),
+        ("procedure?", is_procedure)
+    ];
+
+    let frame = Rc::new(RefCell::new(Frame { variables: HashMap::new(), parent: None }));
+    let heap = Heap { frames: vec![Rc::downgrade(&frame)], threshold: COLLECTION_THRESHOLD };
+
+    let root = Scope {
+        libraries: Rc::new(RefCell::new(Libraries::default())),
+        truthiness,
+        heap: Rc::new(RefCell::new(heap)),
+        frame
+    };
+
+    for &(name, function) in builtins.iter() {
+        root.define_native(name, move |_, arguments| function(arguments));
     }
+    root.define_native("apply", apply);
 
-    if let Primitive::Float(a1) = left {
-        if let Primitive::Integer(a2) = right {
-            return Primitive::Bool(*a1 < f64::from(*a2));
-        }
-        if let Primitive::Float(a2) = right {
-            return Primitive::Bool(a1 < a2);
-        }
+    root.define(String::from(">"), Primitive::Function(convert::function(">", |a: f64, b: f64| a > b)));
+    root.define(String::from("<"), Primitive::Function(convert::function("<", |a: f64, b: f64| a < b)));
+    root.define(String::from("="), Primitive::Function(convert::function("=", |a: f64, b: f64| a == b)));
+
+    root.extend()
+}
+
+/// Add the frames `value` holds references to, directly or through values only it holds, to
+/// `frames`, returning how many values were looked at
+fn referenced_frames(value: &Primitive, frames: &mut Vec<*const RefCell<Frame>>) -> usize {
+    match value {
+        Primitive::Lambda(_, _, closure) | Primitive::CaseLambda(_, closure) | Primitive::Macro(_, _, closure) => {
+            frames.push(Rc::as_ptr(&closure.frame));
+            1
+        },
+        Primitive::Syntax(rules) if Rc::strong_count(rules) == 1 => {
+            frames.push(Rc::as_ptr(&rules.scope().frame));
+            1
+        },
+        Primitive::Pair(car, cdr) => {
+            let mut pair = (car, cdr);
+            let mut visited = 1;
+
+            loop {
+                if Rc::strong_count(pair.0) == 1 {
+                    visited += referenced_frames(pair.0, frames);
+                }
+
+                match pair.1.as_ref() {
+                    Primitive::Pair(car, cdr) if Rc::strong_count(pair.1) == 1 => {
+                        pair = (car, cdr);
+                        visited += 1;
+                    },
+                    other if Rc::strong_count(pair.1) == 1 => break visited + referenced_frames(other, frames),
+                    _ => break visited
+                }
+            }
+        },
+        _ => 1
     }
+}
+
+/// Make `arguments` available to programs as the list of strings returned by `(command-line)`
+pub fn set_command_line(scope: &Scope, arguments: Vec<String>) {
+    let span = Span::default();
+    let arguments = arguments.into_iter()
+        .map(|argument| ParseTree::Element(Primitive::String(argument), span.clone()))
+        .collect();
+    let quote = ParseTree::Element(Primitive::Identifier(String::from("quote")), span.clone());
+    let body = ParseTree::List(vec![quote, ParseTree::List(arguments, span.clone())], span);
 
-    panic!("cannot compare {:?} and {:?}", left, right);
+    scope.define(String::from("command-line"), Primitive::Lambda(Parameters::default(), Rc::new(vec![body]), scope.clone()));
 }
 
-fn addition(list: Vec<Primitive>) -> Primitive {
-    let mut has_float = false;
-    let result = list.iter().fold(0f64, |acc, x|
-              match x {
-                  Primitive::Integer(n) => f64::from(*n) + acc,
-                  Primitive::Float(n) => {
-                      has_float = true;
-                      n + acc
-                  },
-                  _ => panic!("cannot add {:?}", x)
-              });
-    if has_float {
-        Primitive::Float(result)
-    } else {
-        Primitive::Integer(result as i32)
+/// A builtin that only needs its arguments
+type Native = fn(Vec<Primitive>) -> Result<Primitive, EvalError>;
+
+fn expect_arguments(name: &str, list: &[Primitive], count: usize) -> Result<(), EvalError> {
+    if list.len() != count {
+        return Err(EvalError::arity_error(format!("{} expects {} but got {}", name, error::arguments(count), list.len())));
     }
+    Ok(())
 }
 
-fn divide(list: Vec<Primitive>) -> Primitive {
-    let leftmost = match list.first().unwrap() {
-        Primitive::Integer(n) => f64::from(*n),
-        Primitive::Float(n) => *n,
-        n => panic!("can't device {:?}", n)
-    };
+fn not(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("not", &list, 1)?;
+    Ok(Primitive::Bool(!Truthiness::Scheme.is_true(&list[0])))
+}
 
-    let result = list[1..].iter().fold(leftmost, |acc, x|
-        match x {
-            Primitive::Integer(n) => acc / f64::from(*n),
-            Primitive::Float(n) => acc / n,
-            _ => panic!("cannot divide {:?}", x)
-        }
-    );
+fn legacy_not(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("not", &list, 1)?;
+    Ok(Primitive::Bool(!Truthiness::Legacy.is_true(&list[0])))
+}
 
-    return Primitive::Float(result);
+/// Only reached when apply is called from Rust, the interpreter unwraps calls to it itself
+fn apply(context: &Context, list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    let (procedure, arguments) = interpreter::unapply(list)?;
+    context.call(procedure, arguments)
 }
 
-fn subtract(list: Vec<Primitive>) -> Primitive {
-    let mut is_float = false;
+fn cons(mut list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("cons", &list, 2)?;
+    let cdr = list.remove(1);
+    let car = list.remove(0);
 
-    let leftmost = match list.first().unwrap() {
-        Primitive::Integer(n) => f64::from(*n),
-        Primitive::Float(n) => {
-            is_float = true;
-            *n
-        },
-        n => panic!("cant subtract {:?}", n)
+    Ok(Primitive::cons(car, cdr))
+}
+
+fn car(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("car", &list, 1)?;
+
+    match &list[0] {
+        Primitive::Pair(car, _) => Ok(car.as_ref().clone()),
+        other => Err(EvalError::type_error(format!("car expects a pair but got {:?}", other)))
+    }
+}
+
+fn cdr(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("cdr", &list, 1)?;
+
+    match &list[0] {
+        Primitive::Pair(_, cdr) => Ok(cdr.as_ref().clone()),
+        other => Err(EvalError::type_error(format!("cdr expects a pair but got {:?}", other)))
+    }
+}
+
+fn list(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    Ok(Primitive::list(list))
+}
+
+fn is_null(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("null?", &list, 1)?;
+    Ok(Primitive::Bool(list[0] == Primitive::Nil))
+}
+
+fn is_pair(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("pair?", &list, 1)?;
+    Ok(Primitive::Bool(matches!(list[0], Primitive::Pair(_, _))))
+}
+
+fn is_procedure(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    expect_arguments("procedure?", &list, 1)?;
+    Ok(Primitive::Bool(matches!(list[0], Primitive::Function(_) | Primitive::Lambda(..) | Primitive::CaseLambda(..))))
+}
+
+fn addition(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    arithmetic("add", &Primitive::Integer(0), &list, i32::checked_add, |a, b| a + b)
+}
+
+fn divide(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    let mut result = match list.first() {
+        Some(Primitive::Integer(n)) => f64::from(*n),
+        Some(Primitive::Float(n)) => *n,
+        Some(n) => return Err(EvalError::type_error(format!("cannot divide {:?}", n))),
+        None => return Err(EvalError::arity_error("/ expects at least 1 argument but got 0"))
     };
 
-    if list.len() == 1 {
-        if is_float {
-            return Primitive::Float(-leftmost);
-        } else {
-            return Primitive::Integer(-(leftmost as i32));
+    for x in list[1..].iter() {
+        match x {
+            Primitive::Integer(0) => return Err(EvalError::division_by_zero()),
+            Primitive::Integer(n) => result /= f64::from(*n),
+            Primitive::Float(n) => result /= n,
+            _ => return Err(EvalError::type_error(format!("cannot divide {:?}", x)))
         }
     }
 
-    let result = list[1..].iter().fold(leftmost, |acc, x|
-              match x {
-                  Primitive::Integer(n) => acc - f64::from(*n),
-                  Primitive::Float(n) => {
-                      is_float = true;
-                      acc - n
-                  }
-                  _ => panic!("cannot subtract {:?}", x)
-              });
+    Ok(Primitive::Float(result))
+}
 
-    if is_float {
-        Primitive::Float(result)
-    } else {
-        Primitive::Integer(result as i32)
+fn subtract(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    match list.len() {
+        0 => Err(EvalError::arity_error("- expects at least 1 argument but got 0")),
+        1 => arithmetic("subtract", &Primitive::Integer(0), &list, i32::checked_sub, |a, b| a - b),
+        _ => arithmetic("subtract", &list[0], &list[1..], i32::checked_sub, |a, b| a - b)
     }
 }
 
-fn multiply(list: Vec<Primitive>) -> Primitive {
-    let mut has_float = false;
-    let result = list.iter().fold(1f64, |acc, x|
-              match x {
-                  Primitive::Integer(n) => acc * f64::from(*n),
-                  Primitive::Float(n) => {
-                      has_float = true;
-                      acc * n
-                  }
-                  _ => panic!("cannot multiply {:?}", x)
-              });
-    if has_float {
-        Primitive::Float(result)
-    } else {
-        Primitive::Integer(result as i32)
+fn multiply(list: Vec<Primitive>) -> Result<Primitive, EvalError> {
+    arithmetic("multiply", &Primitive::Integer(1), &list, i32::checked_mul, |a, b| a * b)
+}
+
+/// Fold `operands` into `initial`, with `integer` when they are all integers and with `float` as
+/// soon as one of them is a float
+fn arithmetic(verb: &str, initial: &Primitive, operands: &[Primitive],
+              integer: fn(i32, i32) -> Option<i32>, float: fn(f64, f64) -> f64) -> Result<Primitive, EvalError> {
+    let numbers = std::iter::once(initial).chain(operands);
+
+    if let Some(x) = numbers.clone().find(|x| !matches!(x, Primitive::Integer(_) | Primitive::Float(_))) {
+        return Err(EvalError::type_error(format!("cannot {} {:?}", verb, x)));
+    }
+
+    if numbers.clone().any(|x| matches!(x, Primitive::Float(_))) {
+        let mut result = f64::from_value(initial.clone())?;
+
+        for x in operands {
+            result = float(result, f64::from_value(x.clone())?);
+        }
+
+        return Ok(Primitive::Float(result));
     }
+
+    let mut result = i32::from_value(initial.clone())?;
+
+    for x in operands {
+        result = integer(result, i32::from_value(x.clone())?).ok_or_else(|| EvalError::overflow(verb))?;
+    }
+
+    Ok(Primitive::Integer(result))
 }
 
 #[cfg(test)] 
 mod tests {
     use super::*;
+    use super::super::error::ErrorKind;
 
     #[test]
     fn test_subtract() {
@@ -217,7 +523,7 @@ mod tests {
         let b = Primitive::Integer(1);
         let list = vec![a, b];
 
-        assert_eq!(subtract(list), Primitive::Integer(8));
+        assert_eq!(subtract(list), Ok(Primitive::Integer(8)));
     }
 
     #[test]
@@ -226,6 +532,150 @@ mod tests {
         let b = Primitive::Float(2.0);
         let list = vec![a, b];
 
-        assert_eq!(divide(list), Primitive::Float(2.0));
+        assert_eq!(divide(list), Ok(Primitive::Float(2.0)));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let list = vec![Primitive::Integer(4), Primitive::Integer(0)];
+        assert_eq!(divide(list).unwrap_err().kind, ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn test_add_type_error() {
+        let list = vec![Primitive::Integer(4), Primitive::String(String::from("a"))];
+        assert_eq!(addition(list).unwrap_err().kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        let list = vec![Primitive::Integer(100000), Primitive::Integer(100000)];
+        assert_eq!(multiply(list).unwrap_err().kind, ErrorKind::Overflow);
+
+        let list = vec![Primitive::Integer(i32::MIN)];
+        assert_eq!(subtract(list).unwrap_err().kind, ErrorKind::Overflow);
+
+        let list = vec![Primitive::Integer(i32::MAX), Primitive::Integer(1)];
+        assert_eq!(addition(list).unwrap_err().kind, ErrorKind::Overflow);
+
+        let list = vec![Primitive::Integer(i32::MAX), Primitive::Integer(1), Primitive::Float(0.5)];
+        assert_eq!(addition(list), Ok(Primitive::Float(2147483648.5)));
+
+        let list = vec![Primitive::Integer(i32::MIN), Primitive::Integer(-1)];
+        assert_eq!(subtract(list), Ok(Primitive::Integer(i32::MIN + 1)));
+    }
+
+    #[test]
+    fn test_lookup_walks_parent_frames() {
+        let global = standard_env();
+        global.define(String::from("x"), Primitive::Integer(1));
+
+        let local = global.extend();
+        local.define(String::from("y"), Primitive::Integer(2));
+
+        assert_eq!(local.get("x"), Some(Primitive::Integer(1)));
+        assert_eq!(local.get("y"), Some(Primitive::Integer(2)));
+        assert_eq!(global.get("y"), None);
+    }
+
+    #[test]
+    fn test_set_changes_nearest_binding() {
+        let global = standard_env();
+        global.define(String::from("x"), Primitive::Integer(1));
+
+        let local = global.extend();
+        assert!(local.set("x", Primitive::Integer(2)));
+        assert_eq!(global.get("x"), Some(Primitive::Integer(2)));
+
+        local.define(String::from("x"), Primitive::Integer(3));
+        assert!(local.set("x", Primitive::Integer(4)));
+        assert_eq!(global.get("x"), Some(Primitive::Integer(2)));
+
+        assert!(!local.set("y", Primitive::Integer(5)));
+        assert_eq!(local.get("y"), None);
+    }
+
+    #[test]
+    fn test_define_shadows_in_current_frame() {
+        let global = standard_env();
+        global.define(String::from("x"), Primitive::Integer(1));
+
+        let local = global.extend();
+        local.define(String::from("x"), Primitive::Integer(2));
+
+        assert_eq!(local.get("x"), Some(Primitive::Integer(2)));
+        assert_eq!(global.get("x"), Some(Primitive::Integer(1)));
+    }
+
+    #[test]
+    fn test_fresh_scope_keeps_builtins() {
+        let global = standard_env();
+        global.define(String::from("x"), Primitive::Integer(1));
+        global.define(String::from("car"), Primitive::Integer(2));
+
+        let fresh = global.extend().fresh();
+        assert_eq!(fresh.get("x"), None);
+        assert!(matches!(fresh.get("car"), Some(Primitive::Function(_))));
+    }
+
+    #[test]
+    fn test_collect_garbage_frees_cycles() {
+        let global = standard_env();
+        let body = Rc::new(vec![]);
+
+        // a frame binding a closure over itself, as an internal define makes
+        let local = global.extend();
+        local.define(String::from("f"), Primitive::Lambda(Parameters::default(), Rc::clone(&body), local.clone()));
+        let probe = Rc::downgrade(&local.frame);
+
+        // a closure that outlives the frame it was made in, like the result of make-adder
+        let kept = global.extend();
+        kept.define(String::from("n"), Primitive::Integer(1));
+        let closure = Primitive::Lambda(Parameters::default(), body, kept.clone());
+        kept.define(String::from("self"), closure.clone());
+
+        drop(local);
+        drop(kept);
+        assert!(probe.upgrade().is_some());
+
+        global.collect_garbage();
+        assert!(probe.upgrade().is_none());
+
+        match &closure {
+            Primitive::Lambda(_, _, scope) => assert_eq!(scope.get("n"), Some(Primitive::Integer(1))),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_cloned_scope_shares_bindings() {
+        let global = standard_env();
+        let handle = global.clone();
+        handle.define(String::from("x"), Primitive::Integer(1));
+
+        assert_eq!(global.get("x"), Some(Primitive::Integer(1)));
+    }
+
+    #[test]
+    fn test_cons_car_cdr() {
+        let pair = cons(vec![Primitive::Integer(1), Primitive::Integer(2)]).unwrap();
+
+        assert_eq!(car(vec![pair.clone()]), Ok(Primitive::Integer(1)));
+        assert_eq!(cdr(vec![pair.clone()]), Ok(Primitive::Integer(2)));
+        assert_eq!(is_pair(vec![pair]), Ok(Primitive::Bool(true)));
+    }
+
+    #[test]
+    fn test_list_is_nil_terminated() {
+        let items = list(vec![Primitive::Integer(1)]).unwrap();
+
+        assert_eq!(cdr(vec![items]), Ok(Primitive::Nil));
+        assert_eq!(is_null(vec![Primitive::Nil]), Ok(Primitive::Bool(true)));
+        assert_eq!(is_pair(vec![Primitive::Nil]), Ok(Primitive::Bool(false)));
+    }
+
+    #[test]
+    fn test_car_of_empty_list() {
+        assert_eq!(car(vec![Primitive::Nil]).unwrap_err().kind, ErrorKind::TypeError);
     }
 }
@@ -0,0 +1,170 @@
+use std::error::Error;
+use std::fmt;
+use super::types::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    TypeError,
+    ArityError,
+    UnboundVariable,
+    DivisionByZero,
+    Overflow,
+    SyntaxError,
+    LoadError,
+    /// Evaluations nested deeper than the interpreter allows, usually runaway recursion
+    RecursionLimit,
+    /// Raised by `(quit)` to unwind the program, the host decides whether to end the process
+    Exit(i32)
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::TypeError => write!(f, "type error"),
+            ErrorKind::ArityError => write!(f, "arity error"),
+            ErrorKind::UnboundVariable => write!(f, "unbound variable"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::Overflow => write!(f, "integer overflow"),
+            ErrorKind::SyntaxError => write!(f, "syntax error"),
+            ErrorKind::LoadError => write!(f, "load error"),
+            ErrorKind::RecursionLimit => write!(f, "recursion limit exceeded"),
+            ErrorKind::Exit(code) => write!(f, "exit with status {}", code)
+        }
+    }
+}
+
+/// Error raised while evaluating an expression
+///
+/// The `kind` is what host code should match on, `message` is meant for humans. `span` points at
+/// the innermost expression that was being evaluated when the error was raised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Option<Span>
+}
+
+impl EvalError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> EvalError {
+        EvalError { kind, message: message.into(), span: None }
+    }
+
+    /// Record where the error happened, unless a more precise location is already known
+    pub fn with_span(mut self, span: &Span) -> EvalError {
+        if self.span.is_none() {
+            self.span = Some(span.clone());
+        }
+        self
+    }
+
+    pub fn type_error(message: impl Into<String>) -> EvalError {
+        EvalError::new(ErrorKind::TypeError, message)
+    }
+
+    pub fn arity_error(message: impl Into<String>) -> EvalError {
+        EvalError::new(ErrorKind::ArityError, message)
+    }
+
+    pub fn unbound_variable(name: &str) -> EvalError {
+        EvalError::new(ErrorKind::UnboundVariable, name)
+    }
+
+    pub fn division_by_zero() -> EvalError {
+        EvalError::new(ErrorKind::DivisionByZero, "attempted to divide by zero")
+    }
+
+    /// An integer result that does not fit in 32 bits
+    pub fn overflow(verb: &str) -> EvalError {
+        EvalError::new(ErrorKind::Overflow, format!("cannot {}, the result is out of range", verb))
+    }
+
+    pub fn syntax_error(message: impl Into<String>) -> EvalError {
+        EvalError::new(ErrorKind::SyntaxError, message)
+    }
+
+    pub fn load_error(message: impl Into<String>) -> EvalError {
+        EvalError::new(ErrorKind::LoadError, message)
+    }
+
+    pub fn recursion_limit(limit: usize) -> EvalError {
+        EvalError::new(ErrorKind::RecursionLimit, format!("evaluation nested more than {} levels deep", limit))
+    }
+
+    pub fn exit(code: i32) -> EvalError {
+        EvalError::new(ErrorKind::Exit(code), "program quit")
+    }
+}
+
+/// `count` followed by "argument" or "arguments", for arity error messages
+pub fn arguments(count: usize) -> String {
+    match count {
+        1 => String::from("1 argument"),
+        count => format!("{} arguments", count)
+    }
+}
+
+impl From<ParseError> for EvalError {
+    fn from(error: ParseError) -> EvalError {
+        EvalError::syntax_error(error.message).with_span(&error.span)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write_located(f, span, &format!("{}: {}", self.kind, self.message)),
+            None => write!(f, "{}: {}", self.kind, self.message)
+        }
+    }
+}
+
+impl Error for EvalError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseErrorKind {
+    UnclosedList,
+    UnexpectedCloseParen,
+    UnterminatedString,
+    UnterminatedComment,
+    InvalidNumber,
+    InvalidEscape,
+    MisplacedDot,
+    MissingDatum
+}
+
+/// Error raised while reading source code, pointing at the offending token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub span: Span
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, message: impl Into<String>, span: &Span) -> ParseError {
+        ParseError { kind, message: message.into(), span: span.clone() }
+    }
+
+    /// Whether more input could complete the source, as opposed to it being malformed
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind,
+            ParseErrorKind::UnclosedList |
+            ParseErrorKind::UnterminatedString |
+            ParseErrorKind::UnterminatedComment)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_located(f, &self.span, &format!("parse error: {}", self.message))
+    }
+}
+
+impl Error for ParseError {}
+
+/// Write `message` prefixed with `file:line:col` and followed by the offending source line
+fn write_located(f: &mut fmt::Formatter<'_>, span: &Span, message: &str) -> fmt::Result {
+    writeln!(f, "{}: {}", span, message)?;
+    writeln!(f, "    {}", span.snippet())?;
+    write!(f, "    {}^", " ".repeat(span.column.saturating_sub(1)))
+}
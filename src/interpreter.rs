@@ -1,287 +1,1041 @@
 use super::types::*;
-use super::env::Scope;
-use std::process;
-
-pub fn interpret(input: ParseTree, scope: Scope, global: bool) -> (Primitive, Scope) {
-    match input {
-        ParseTree::List(list) if list.len() > 0 => {
-            if let ParseTree::Element(Primitive::Identifier(leftmost)) = &list[0] {
-                if leftmost == "binding" {
-                    println!("{:?}", scope);
-                } else if leftmost == "print" {
-                    let (result, _) = interpret(list[1].clone(), scope.clone(), false);
-                    println!("{:?}", result);
-                } else if leftmost == "quit" {
-                    process::exit(0x00);
-                } else if leftmost == "define" {
-                    let arguments = list[1].clone();
-                    let body;
-
-                    // Special form to handle:
-                    //
-                    //  (define (foo x)
-                    //    (define result (* x x))
-                    //    result
-                    //
-                    if list[2..].len() > 1 {
-                        body = ParseTree::List(list[2..].to_vec());
-                    } else {
-                        body = list[2].clone();
+use super::env::{Scope, Truthiness};
+use super::error::EvalError;
+use super::library;
+use super::syntax;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// How deeply evaluations may nest, which bounds non-tail recursion so that a runaway program
+/// stops with a `RecursionLimit` error instead of overflowing the stack of the host
+///
+/// Each level takes up to about 64 KiB of stack in debug builds and 8 KiB in optimized ones, so
+/// the thread running the interpreter needs a stack large enough for the limit.
+pub const RECURSION_LIMIT: usize = 10_000;
+
+thread_local! {
+    /// How many evaluations are in progress on this thread
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts an evaluation as in progress for as long as it is alive
+struct Nesting;
+
+impl Nesting {
+    fn enter() -> Result<Nesting, EvalError> {
+        DEPTH.with(|depth| {
+            if depth.get() >= RECURSION_LIMIT {
+                return Err(EvalError::recursion_limit(RECURSION_LIMIT));
+            }
+
+            depth.set(depth.get() + 1);
+            Ok(Nesting)
+        })
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Evaluate `input` in `scope`
+///
+/// When `global` is set the input is the root of a program and each of its forms is evaluated in
+/// order. Expressions in tail position (`if` branches, the last expression of a body, the chosen
+/// `cond` clause, the last operand of `and`/`or`, the bodies of `begin`, `let` and similar forms
+/// and macro expansions) are evaluated by looping
+/// rather than recursing, so tail calls run in constant stack space.
+///
+/// Errors are tagged with the span of the innermost expression that was being evaluated.
+pub fn interpret(input: ParseTree, scope: &Scope, global: bool) -> Result<Primitive, EvalError> {
+    if global {
+        if let ParseTree::List(forms, _) = input {
+            let mut result = Primitive::Nil;
+
+            for form in forms {
+                result = interpret(form, scope, false)?;
+            }
+
+            return Ok(result);
+        }
+    }
+
+    let mut span = input.span().clone();
+    let _nesting = Nesting::enter().map_err(|error| error.with_span(&span))?;
+    evaluate(input, scope, &mut span).map_err(|error| error.with_span(&span))
+}
+
+/// The evaluation loop behind `interpret`, which keeps `span` pointing at the expression currently
+/// being evaluated
+fn evaluate(input: ParseTree, scope: &Scope, span: &mut Span) -> Result<Primitive, EvalError> {
+    let mut input = input;
+    let mut scope = scope.clone();
+
+    loop {
+        *span = input.span().clone();
+
+        let list = match input {
+            ParseTree::List(list, _) if !list.is_empty() => list,
+            ParseTree::List(_, _) => return Ok(Primitive::Nil), // empty case
+            ParseTree::Element(Primitive::Identifier(ref id), _) => {
+                match syntax::lookup(&scope, id) {
+                    Some(primitive) => return Ok(primitive),
+                    None => return Err(EvalError::unbound_variable(syntax::base_name(id)))
+                }
+            }
+            ParseTree::Element(primitive, _) => return Ok(primitive)
+        };
+
+        if let ParseTree::Element(Primitive::Identifier(name), _) = &list[0] {
+            // special forms are recognized by the name they were written as, even when a macro
+            // expansion renamed them
+            let leftmost = syntax::base_name(name);
+
+            if leftmost == "binding" {
+                println!("{:?}", scope);
+                return Ok(Primitive::Nil);
+            } else if leftmost == "print" {
+                expect_form("print", &list, 2, 2)?;
+                let result = interpret(list[1].clone(), &scope, false)?;
+                println!("{:?}", result);
+                return Ok(result);
+            } else if leftmost == "quote" {
+                expect_form("quote", &list, 2, 2)?;
+                return Ok(list[1].clone().into_datum());
+            } else if leftmost == "quasiquote" {
+                expect_form("quasiquote", &list, 2, 2)?;
+                return quasiquote(&list[1], 1, &scope);
+            } else if leftmost == "unquote" || leftmost == "unquote-splicing" {
+                return Err(EvalError::syntax_error(format!("{} outside of quasiquote", leftmost)));
+            } else if leftmost == "load" {
+                expect_form("load", &list, 2, 2)?;
+                return library::load(&list[1], &scope);
+            } else if leftmost == "define-library" {
+                expect_form("define-library", &list, 2, usize::MAX)?;
+                return library::define_library(&list[1], &list[2..], &scope);
+            } else if leftmost == "import" {
+                return library::import(&list[1..], &scope);
+            } else if leftmost == "quit" { // (quit) or (quit status)
+                expect_form("quit", &list, 1, 2)?;
+
+                let code = match list.get(1) {
+                    Some(status) => match interpret(status.clone(), &scope, false)? {
+                        Primitive::Integer(code) => code,
+                        other => return Err(EvalError::type_error(format!("quit expects an integer status, got {:?}", other)))
+                    },
+                    None => 0
+                };
+
+                return Err(EvalError::exit(code));
+            } else if leftmost == "define" {
+                expect_form("define", &list, 3, usize::MAX)?;
+                let arguments = list[1].clone();
+
+                // Special form to handle:
+                //
+                //  (define (foo x)
+                //    (define result (* x x))
+                //    result
+                //
+                let body = list[2..].to_vec();
+
+                if let ParseTree::Element(Primitive::Identifier(id), _) = &arguments { // (define x 2)
+                    expect_form("define", &list, 3, 3)?;
+                    let result = interpret(list[2].clone(), &scope, false)?;
+                    return Ok(set_variable(id.clone(), result, &scope));
+                } else if let ParseTree::List(signature, _) = arguments { // (define (x) (* x x))
+                    return define_procedure(signature, body, &scope);
+                } else {
+                    return Err(EvalError::syntax_error(format!("cannot define {:?}", arguments)));
+                }
+            } else if leftmost == "define-syntax" {
+                expect_form("define-syntax", &list, 3, 3)?;
+                let name = syntax::define_syntax(&list[1], &list[2], &scope)?;
+                return Ok(Primitive::Identifier(name));
+            } else if leftmost == "defmacro" { // (defmacro name (args) body)
+                expect_form("defmacro", &list, 4, usize::MAX)?;
+
+                match &list[2] {
+                    ParseTree::List(signature, _) => return define_macro(&list[1], signature, list[3..].to_vec(), &scope),
+                    rest @ ParseTree::Element(Primitive::Identifier(_), _) => {
+                        return define_macro(&list[1], &[dot(rest.span()), rest.clone()], list[3..].to_vec(), &scope);
+                    },
+                    other => return Err(EvalError::syntax_error("defmacro expects a list of arguments").with_span(other.span()))
+                }
+            } else if leftmost == "define-macro" { // (define-macro (name args) body)
+                expect_form("define-macro", &list, 3, usize::MAX)?;
+
+                match &list[1] {
+                    ParseTree::List(signature, _) if !signature.is_empty() => {
+                        return define_macro(&signature[0], &signature[1..], list[2..].to_vec(), &scope);
+                    },
+                    other => return Err(EvalError::syntax_error("define-macro expects (name args...)").with_span(other.span()))
+                }
+            } else if leftmost == "macroexpand" || leftmost == "macroexpand-1" {
+                expect_form(leftmost, &list, 2, 2)?;
+                let form = interpret(list[1].clone(), &scope, false)?;
+                let mut form = ParseTree::from_datum(form, list[1].span())?;
+
+                while let Some(expansion) = expand_once(&form, &scope)? {
+                    form = expansion;
+
+                    if leftmost == "macroexpand-1" {
+                        break;
+                    }
+                }
+
+                return Ok(form.into_datum());
+            } else if leftmost == "let-syntax" || leftmost == "letrec-syntax" {
+                expect_form(leftmost, &list, 3, usize::MAX)?;
+                let local_scope = scope.extend();
+
+                match &list[1] {
+                    ParseTree::List(bindings, _) => {
+                        for binding in bindings {
+                            match binding {
+                                ParseTree::List(binding, _) if binding.len() == 2 => {
+                                    syntax::define_syntax(&binding[0], &binding[1], &local_scope)?;
+                                },
+                                _ => return Err(EvalError::syntax_error(format!("bad {} binding", leftmost)).with_span(binding.span()))
+                            }
+                        }
+                    },
+                    _ => return Err(EvalError::syntax_error(format!("bad {} form", leftmost)))
+                }
+
+                scope = local_scope;
+                input = evaluate_leading(&list[2..], &scope)?;
+                continue;
+            } else if leftmost == "set!" { // (set! x 2)
+                expect_form("set!", &list, 3, 3)?;
+
+                let name = match &list[1] {
+                    ParseTree::Element(Primitive::Identifier(id), _) => id,
+                    other => return Err(EvalError::syntax_error(format!("cannot assign to {:?}", other)).with_span(other.span()))
+                };
+                let value = interpret(list[2].clone(), &scope, false)?;
+
+                if !syntax::assign(&scope, name, value) {
+                    return Err(EvalError::unbound_variable(syntax::base_name(name)).with_span(list[1].span()));
+                }
+
+                return Ok(Primitive::Nil);
+            } else if leftmost == "case-lambda" { // (case-lambda ((x) x) ((x y) y))
+                let mut clauses = vec![];
+
+                for clause in list[1..].iter() {
+                    match clause {
+                        ParseTree::List(clause, _) if clause.len() >= 2 => {
+                            let arguments = match &clause[0] {
+                                ParseTree::List(signature, _) => parameters(signature)?,
+                                rest @ ParseTree::Element(Primitive::Identifier(_), _) => parameters(&[dot(rest.span()), rest.clone()])?,
+                                other => return Err(EvalError::syntax_error("case-lambda expects a list of arguments").with_span(other.span()))
+                            };
+                            clauses.push((arguments, Rc::new(clause[1..].to_vec())));
+                        },
+                        _ => return Err(EvalError::syntax_error("case-lambda clauses must be (arguments body...)").with_span(clause.span()))
                     }
+                }
+
+                return Ok(Primitive::CaseLambda(Rc::new(clauses), scope.clone()));
+            } else if leftmost == "let" {
+                expect_form("let", &list, 3, usize::MAX)?;
+
+                // named let, (let loop ((i 0)) body), binds loop to a procedure over the body
+                if let ParseTree::Element(Primitive::Identifier(name), _) = &list[1] {
+                    expect_form("let", &list, 4, usize::MAX)?;
+                    let bindings = let_bindings("let", &list[2], true)?;
+                    let body = list[3..].to_vec();
+
+                    let (variables, inits): (Vec<_>, Vec<_>) = bindings.into_iter().unzip();
+                    let arguments = Parameters { name: Some(name.clone()), ..Parameters::required(variables) };
+                    let values = flatten_tree(inits, &scope)?;
+
+                    let loop_scope = scope.extend();
+                    loop_scope.define(name.clone(), Primitive::Lambda(arguments.clone(), Rc::new(body.clone()), loop_scope.clone()));
+
+                    scope = bind_arguments(&arguments, values, &loop_scope)?;
+                    input = evaluate_leading(&body, &scope)?;
+                    continue;
+                }
+
+                let local_scope = scope.extend();
+
+                for (variable, init) in let_bindings("let", &list[1], true)? {
+                    let value = interpret(init, &scope, false)?;
+                    local_scope.define(variable, value);
+                }
+
+                scope = local_scope;
+                input = evaluate_leading(&list[2..], &scope)?;
+                continue;
+            } else if leftmost == "let*" {
+                expect_form("let*", &list, 3, usize::MAX)?;
+                let mut local_scope = scope.extend();
+
+                // each binding gets its own frame so later ones can shadow earlier ones
+                for (variable, init) in let_bindings("let*", &list[1], false)? {
+                    let value = interpret(init, &local_scope, false)?;
+                    local_scope = local_scope.extend();
+                    local_scope.define(variable, value);
+                }
+
+                scope = local_scope;
+                input = evaluate_leading(&list[2..], &scope)?;
+                continue;
+            } else if leftmost == "letrec" || leftmost == "letrec*" {
+                expect_form(leftmost, &list, 3, usize::MAX)?;
+                let local_scope = scope.extend();
+                let bindings = let_bindings(leftmost, &list[1], true)?;
 
-                    if let ParseTree::Element(Primitive::Identifier(id)) = arguments { // (define x 2)
-                        let (result, new_scope) = interpret(body, scope, false);
-                        return set_variable(id, result, new_scope);
-                    } else if let ParseTree::List(signature) = arguments { // (define (x) (* x x))
-                        return define_procedure(signature, body, scope);
+                if leftmost == "letrec" {
+                    // every init is evaluated before any variable is bound
+                    let (variables, inits): (Vec<_>, Vec<_>) = bindings.into_iter().unzip();
+                    let values = flatten_tree(inits, &local_scope)?;
+
+                    for (variable, value) in variables.into_iter().zip(values) {
+                        local_scope.define(variable, value);
                     }
-                } else if leftmost == "lambda" {
-                    // We need an object that can hold the contents of lambda
-                    let arguments = list[1].clone();
-                    let body;
-
-                    // Special form to handle:
-                    //
-                    //  ((lambda (x)
-                    //    (define square (* x x))
-                    //    (square x)) 2)
-                    //
-                    if list[2..].len() > 1 {
-                        body = ParseTree::List(list[2..].to_vec());
-                    } else {
-                        body = list[2].clone();
+                } else {
+                    for (variable, init) in bindings {
+                        let value = interpret(init, &local_scope, false)?;
+                        local_scope.define(variable, value);
                     }
+                }
+
+                scope = local_scope;
+                input = evaluate_leading(&list[2..], &scope)?;
+                continue;
+            } else if leftmost == "lambda" {
+                expect_form("lambda", &list, 3, usize::MAX)?;
+                // We need an object that can hold the contents of lambda
+                let arguments = list[1].clone();
+
+                // Special form to handle:
+                //
+                //  ((lambda (x)
+                //    (define square (* x x))
+                //    (square x)) 2)
+                //
+                let body = list[2..].to_vec();
+
+                match arguments {
+                    ParseTree::List(signature, _) => return define_lambda(signature, body, &scope), // (lambda (x) (* x x))
+                    ParseTree::Element(Primitive::Identifier(_), _) => { // (lambda args (car args))
+                        return define_lambda(vec![dot(arguments.span()), arguments.clone()], body, &scope);
+                    },
+                    _ => return Err(EvalError::syntax_error(format!("lambda expects a list of arguments but found {:?}", arguments)))
+                }
+            } else if leftmost == "if" {
+                expect_form("if", &list, 3, 4)?;
+                let result = interpret(list[1].clone(), &scope, false)?;
 
-                    if let ParseTree::List(signature) = arguments { // (define (x) (* x x))
-                        return (define_lambda(signature, body, scope.clone()), scope);
-                    } else {
-                        panic!("not a list");
+                if scope.truthiness().is_true(&result) {
+                    input = list[2].clone();
+                    continue;
+                } else if let Some(alternative) = list.get(3) {
+                    input = alternative.clone();
+                    continue;
+                } else {
+                    return Ok(Primitive::Nil);
+                }
+            } else if leftmost == "begin" {
+                // no new frame, so definitions in a top level begin stay top level
+                input = evaluate_leading(&list[1..], &scope)?;
+                continue;
+            } else if leftmost == "when" || leftmost == "unless" {
+                expect_form(leftmost, &list, 3, usize::MAX)?;
+                let result = interpret(list[1].clone(), &scope, false)?;
+
+                if scope.truthiness().is_true(&result) == (leftmost == "when") {
+                    input = evaluate_leading(&list[2..], &scope)?;
+                    continue;
+                } else {
+                    return Ok(Primitive::Nil);
+                }
+            } else if leftmost == "case" {
+                expect_form("case", &list, 2, usize::MAX)?;
+                let key = interpret(list[1].clone(), &scope, false)?;
+                let mut body = None;
+
+                for clause in list[2..].iter() {
+                    let expressions = match clause {
+                        ParseTree::List(expressions, _) if expressions.len() >= 2 => expressions,
+                        _ => return Err(EvalError::syntax_error(format!("malformed case clause {:?}", clause)).with_span(clause.span()))
+                    };
+
+                    let matched = match &expressions[0] {
+                        data if is_keyword(data, "else") => true,
+                        ParseTree::List(data, _) => data.iter().any(|datum| datum.clone().into_datum() == key),
+                        other => return Err(EvalError::syntax_error("case clauses must start with a list of data or else").with_span(other.span()))
+                    };
+
+                    if matched {
+                        body = Some(&expressions[1..]);
+                        break;
                     }
-                } else if leftmost == "if" {
-                    let predicate = list[1].clone();
-                    let consequent = list[2].clone();
-                    let alternative = list[3].clone();
+                }
+
+                match body {
+                    // (case x ((1) => receiver)) calls receiver with the key
+                    Some([arrow, receiver]) if is_keyword(arrow, "=>") => {
+                        input = ParseTree::List(vec![receiver.clone(), quoted(key, receiver.span())], receiver.span().clone());
+                        continue;
+                    },
+                    Some(body) => {
+                        input = evaluate_leading(body, &scope)?;
+                        continue;
+                    },
+                    None => return Ok(Primitive::Nil)
+                }
+            } else if leftmost == "do" { // (do ((var init step) ...) (test result ...) command ...)
+                expect_form("do", &list, 3, usize::MAX)?;
+                let variables = do_bindings(&list[1])?;
+
+                let (test, results) = match &list[2] {
+                    ParseTree::List(clause, _) if !clause.is_empty() => (&clause[0], &clause[1..]),
+                    other => return Err(EvalError::syntax_error("do expects a (test result ...) clause").with_span(other.span()))
+                };
 
-                    let (result, _) = interpret(predicate.clone(), scope.clone(), false);
+                let mut local_scope = scope.extend();
+
+                for (variable, init, _) in &variables {
+                    let value = interpret(init.clone(), &scope, false)?;
+                    local_scope.define(variable.clone(), value);
+                }
 
-                    if result == Primitive::Bool(true) {
-                        return interpret(consequent, scope.clone(), false);
-                    } else {
-                        return interpret(alternative, scope.clone(), false);
+                while !scope.truthiness().is_true(&interpret(test.clone(), &local_scope, false)?) {
+                    for command in list[3..].iter() {
+                        interpret(command.clone(), &local_scope, false)?;
                     }
-                } else if leftmost == "and" {
-                    let mut last_result: Primitive = Primitive::Bool(true);
 
-                    for expression in list[1..].into_iter() {
-                        let (result, _) = interpret(expression.clone(), scope.clone(), false);
-                        last_result = result;
+                    // every iteration gets fresh bindings, so closures made in one keep its values
+                    let next_scope = scope.extend();
 
-                        if falsy(&last_result) {
-                            return (Primitive::Bool(false), scope);
-                        }
+                    for (variable, _, step) in &variables {
+                        let value = match step {
+                            Some(step) => interpret(step.clone(), &local_scope, false)?,
+                            None => local_scope.get(variable).unwrap_or(Primitive::Nil)
+                        };
+                        next_scope.define(variable.clone(), value);
                     }
 
-                    return (last_result, scope);
-                } else if leftmost == "or" {
-                    let mut last_result: Primitive;
+                    local_scope = next_scope;
+                }
 
-                    for expression in list[1..].into_iter() {
-                        let (result, _) = interpret(expression.clone(), scope.clone(), false);
-                        last_result = result;
+                if results.is_empty() {
+                    return Ok(Primitive::Nil);
+                }
 
-                        if truthy(&last_result) {
-                            return (last_result, scope);
-                        }
-                    }
+                scope = local_scope;
+                input = evaluate_leading(results, &scope)?;
+                continue;
+            } else if leftmost == "and" {
+                if list.len() == 1 {
+                    return Ok(Primitive::Bool(true));
+                }
 
-                    return (Primitive::Nil, scope);
-                } else if leftmost == "cond" {
-                    for clause in list[1..].into_iter() {
-                        if let ParseTree::List(expressions) = clause {
-                            let predicate = &expressions[0];
-                            let expression = &expressions[1];
-                            let (result, _) = interpret(predicate.clone(), scope.clone(), false);
-
-                            // evaluate if predicate is true or "else" is present
-                            // e.g. ((cond (else something))
-                            if result == Primitive::Bool(true) ||
-                               result == Primitive::Identifier(String::from("else")) {
-                                return interpret(expression.clone(), scope.clone(), false);
-                            }
-                        }
+                // legacy and answers false for any false operand, so the last one is checked too
+                let last = match scope.truthiness() {
+                    Truthiness::Scheme => list.len() - 1,
+                    Truthiness::Legacy => list.len()
+                };
+
+                let mut result = Primitive::Bool(true);
+
+                for expression in list[1..last].iter() {
+                    result = interpret(expression.clone(), &scope, false)?;
+
+                    if !scope.truthiness().is_true(&result) {
+                        return Ok(Primitive::Bool(false));
                     }
-                    return (Primitive::Nil, scope);
-                } else if let Some(Primitive::Lambda(arguments, body)) = scope.clone().variables.get(leftmost) {
-                    let (params, _) = flatten_tree(list[1..].to_vec(), scope.clone());
-
-                    let body: ParseTree = *(body.clone());
-                    return apply(arguments.to_vec(), params, body.clone(), scope.clone());
-                } else if global == false {
-                    match scope.native_procedures.get(leftmost) {
-                        Some(Primitive::Function(function)) => {
-                            let (results, new_scope) = flatten_tree(list[1..].to_vec(), scope.clone());
-                            return (function(results.to_vec()), new_scope)
-                        },
-                        _ => {
-                            return (Primitive::String(String::from(format!("undefined procedure {:?}", leftmost))), scope);
+                }
+
+                if last == list.len() {
+                    return Ok(result);
+                }
+
+                input = list[last].clone();
+                continue;
+            } else if leftmost == "or" {
+                // legacy or answers nil when no operand is true, so the last one is checked too
+                if scope.truthiness() == Truthiness::Legacy {
+                    for expression in list[1..].iter() {
+                        let result = interpret(expression.clone(), &scope, false)?;
+
+                        if scope.truthiness().is_true(&result) {
+                            return Ok(result);
                         }
                     }
+
+                    return Ok(Primitive::Nil);
                 }
-            }
 
-            let (results, mut new_scope) = flatten_tree(list, scope.clone());
+                if list.len() == 1 {
+                    return Ok(Primitive::Bool(false));
+                }
 
-            // if the leftmost primitive is a lambda then execute the rest of the list using the
-            // lambda function.
-            if let Primitive::Lambda(arguments, body) = results.first().unwrap().clone() {
-                let params = results[1..].to_vec();
-                let body: ParseTree = *body;
+                for expression in list[1..list.len() - 1].iter() {
+                    let result = interpret(expression.clone(), &scope, false)?;
 
-                if params.len() > 0 {
-                    return apply(arguments, params, body, new_scope);
+                    if scope.truthiness().is_true(&result) {
+                        return Ok(result);
+                    }
                 }
-            }
 
-            let last_result = results.last().unwrap().clone();
+                input = list[list.len() - 1].clone();
+                continue;
+            } else if leftmost == "cond" {
+                let clauses = &list[1..];
+                let mut chosen = None;
 
-            if !global {
-                new_scope = scope;
-            }
+                for (index, clause) in clauses.iter().enumerate() {
+                    let expressions = match clause {
+                        ParseTree::List(expressions, _) if !expressions.is_empty() => expressions,
+                        _ => return Err(EvalError::syntax_error(format!("malformed cond clause {:?}", clause)).with_span(clause.span()))
+                    };
+
+                    // else is recognized by name rather than by evaluating it
+                    if is_keyword(&expressions[0], "else") {
+                        if index != clauses.len() - 1 || expressions.len() < 2 {
+                            return Err(EvalError::syntax_error("else must be the last cond clause and have a body").with_span(clause.span()));
+                        }
+
+                        chosen = Some((None, &expressions[1..]));
+                        break;
+                    }
+
+                    let result = interpret(expressions[0].clone(), &scope, false)?;
 
-            if results.len() == 1 {
-                return (results[0].clone(), new_scope)
+                    if scope.truthiness().is_true(&result) {
+                        chosen = Some((Some(result), &expressions[1..]));
+                        break;
+                    }
+                }
+
+                match chosen {
+                    // (cond (test)) returns the value of the test
+                    Some((Some(result), [])) => return Ok(result),
+                    // (cond (test => receiver)) calls receiver with the value of the test
+                    Some((Some(result), [arrow, receiver])) if is_keyword(arrow, "=>") => {
+                        input = ParseTree::List(vec![receiver.clone(), quoted(result, receiver.span())], receiver.span().clone());
+                        continue;
+                    },
+                    Some((_, [arrow, ..])) if is_keyword(arrow, "=>") => {
+                        return Err(EvalError::syntax_error("=> must be followed by exactly one receiver").with_span(arrow.span()));
+                    },
+                    Some((_, body)) => {
+                        input = evaluate_leading(body, &scope)?;
+                        continue;
+                    },
+                    None => return Ok(Primitive::Nil)
+                }
             } else {
-                return (last_result, new_scope)
+                match &syntax::lookup(&scope, name) {
+                    Some(Primitive::Syntax(rules)) => {
+                        input = rules.expand(&list, span)?;
+                        continue;
+                    },
+                    Some(Primitive::Macro(arguments, body, closure)) => {
+                        input = expand_macro(arguments, body, closure, &list, span)?;
+                        continue;
+                    },
+                    Some(_) => {},
+                    None => return Err(EvalError::unbound_variable(leftmost).with_span(list[0].span()))
+                }
             }
-        },
-        ParseTree::List(_) => (Primitive::Nil, scope), // empty case
-        ParseTree::Element(primitive) => {
-            match primitive {
-                Primitive::Identifier(id) => {
-                    match scope.variables.get(&id) {
-                        Some(primitive) => return (primitive.clone(), scope),
-                        _ => return (Primitive::Identifier(id), scope)
+        }
+
+        let mut head = interpret(list[0].clone(), &scope, false)?;
+        let mut values = flatten_tree(list[1..].to_vec(), &scope)?;
+
+        // the builtin apply is unwrapped here rather than called as a native, so the call it makes
+        // is a tail call
+        while matches!(&head, Primitive::Function(function) if scope.is_builtin(function, "apply")) {
+            let (procedure, arguments) = unapply(values)?;
+            head = procedure;
+            values = arguments;
+        }
+
+        match head {
+            procedure @ (Primitive::Function(_) | Primitive::Lambda(..) | Primitive::CaseLambda(..)) => {
+                match apply(procedure, values, &scope)? {
+                    Application::Value(result) => return Ok(result),
+                    Application::Tail(local_scope, body) => {
+                        scope = local_scope;
+                        input = evaluate_leading(&body, &scope)?;
+                        continue;
                     }
                 }
-                _ => return (primitive, scope)
-            }
+            },
+            other => return Err(EvalError::type_error(format!("cannot call {:?}", other)).with_span(list[0].span()))
         }
     }
 }
 
-fn flatten_tree(list: Vec<ParseTree>, scope: Scope) -> (Vec<Primitive>, Scope) {
-    let mut new_scope = scope.clone();
+/// Check that a special form was given between `min` and `max` elements (including its name)
+fn expect_form(name: &str, list: &[ParseTree], min: usize, max: usize) -> Result<(), EvalError> {
+    if list.len() < min || list.len() > max {
+        return Err(EvalError::syntax_error(format!("bad {} form", name)));
+    }
+    Ok(())
+}
+
+fn flatten_tree(list: Vec<ParseTree>, scope: &Scope) -> Result<Vec<Primitive>, EvalError> {
     let mut results: Vec<Primitive> = vec![];
 
     for element in list {
-        let (result, updated_scope) = interpret(element, new_scope, false);
-        results.push(result);
-        new_scope = updated_scope;
+        results.push(interpret(element, scope, false)?);
+    }
+
+    Ok(results)
+}
+
+fn set_variable(label: String, value: Primitive, scope: &Scope) -> Primitive {
+    scope.define(label.clone(), value);
+    Primitive::Identifier(label)
+}
+
+/// Build a closure over `scope`, the environment the lambda expression was evaluated in
+fn define_lambda(signature: Vec<ParseTree>, body: Vec<ParseTree>, scope: &Scope) -> Result<Primitive, EvalError> {
+    Ok(Primitive::Lambda(parameters(&signature)?, Rc::new(body), scope.clone()))
+}
+
+/// Read a parameter list, `(required ... #!optional optional ... . rest)`
+///
+/// Optional parameters are either a name or `(name default)`. `#!rest name` can be used in place
+/// of `. name`.
+fn parameters(signature: &[ParseTree]) -> Result<Parameters, EvalError> {
+    let mut parameters = Parameters::default();
+    let mut optional = false;
+    let mut names: Vec<&str> = vec![];
+
+    let mut index = 0;
+
+    while index < signature.len() {
+        let argument = &signature[index];
+
+        match argument {
+            ParseTree::Element(Primitive::Identifier(id), _) if is_keyword(argument, ".") || is_keyword(argument, "#!rest") => {
+                match &signature[index + 1..] {
+                    [ParseTree::Element(Primitive::Identifier(rest), _)] => {
+                        names.push(rest);
+                        parameters.rest = Some(rest.clone());
+                        break;
+                    },
+                    _ => return Err(EvalError::syntax_error(format!("{} must be followed by a single parameter", id)).with_span(argument.span()))
+                }
+            },
+            _ if is_keyword(argument, "#!optional") && !optional => optional = true,
+            ParseTree::Element(Primitive::Identifier(id), _) if optional => {
+                names.push(id);
+                parameters.optional.push((id.clone(), None));
+            },
+            ParseTree::Element(Primitive::Identifier(id), _) => {
+                names.push(id);
+                parameters.required.push(id.clone());
+            },
+            ParseTree::List(default, _) if optional && default.len() == 2 => match &default[0] {
+                ParseTree::Element(Primitive::Identifier(id), _) => {
+                    names.push(id);
+                    parameters.optional.push((id.clone(), Some(default[1].clone())));
+                },
+                other => return Err(EvalError::syntax_error(format!("expected identifier in argument list but found {:?}", other)).with_span(other.span()))
+            },
+            _ => {
+                let error = EvalError::syntax_error(format!("expected identifier in argument list but found {:?}", argument));
+                return Err(error.with_span(argument.span()));
+            }
+        }
+
+        index += 1;
+    }
+
+    if let Some((_, name)) = names.iter().enumerate().find(|(index, name)| names[..*index].contains(name)) {
+        return Err(EvalError::syntax_error(format!("parameter {} is given more than once", name)));
+    }
+
+    Ok(parameters)
+}
+
+/// A `.` marking the rest parameter, for forms like `(lambda args ...)` that imply one
+fn dot(span: &Span) -> ParseTree {
+    ParseTree::Element(Primitive::Identifier(String::from(".")), span.clone())
+}
+
+fn define_procedure(signature: Vec<ParseTree>, body: Vec<ParseTree>, scope: &Scope) -> Result<Primitive, EvalError> {
+    let name = match signature.first() {
+        Some(ParseTree::Element(Primitive::Identifier(id), _)) => id.clone(),
+        Some(other) => return Err(EvalError::syntax_error(format!("expected procedure name but found {:?}", other)).with_span(other.span())),
+        None => return Err(EvalError::syntax_error("expected procedure name"))
+    };
+    let arguments = Parameters { name: Some(name.clone()), ..parameters(&signature[1..])? };
+    let lambda = Primitive::Lambda(arguments, Rc::new(body), scope.clone());
+
+    Ok(set_variable(name, lambda, scope))
+}
+
+/// Check the shape of a binding list, `((variable init) ...)`, for the `let` family of forms
+///
+/// When `distinct` is set the same variable may not be bound twice.
+fn let_bindings(form: &str, bindings: &ParseTree, distinct: bool) -> Result<Vec<(String, ParseTree)>, EvalError> {
+    let list = match bindings {
+        ParseTree::List(list, _) => list,
+        _ => return Err(EvalError::syntax_error(format!("{} expects a list of bindings", form)).with_span(bindings.span()))
+    };
+
+    let mut result: Vec<(String, ParseTree)> = vec![];
+
+    for binding in list {
+        match binding {
+            ParseTree::List(pair, _) if pair.len() == 2 => match &pair[0] {
+                ParseTree::Element(Primitive::Identifier(variable), _) => {
+                    if distinct && result.iter().any(|(bound, _)| bound == variable) {
+                        return Err(EvalError::syntax_error(format!("{} binds {} more than once", form, variable)).with_span(binding.span()));
+                    }
+
+                    result.push((variable.clone(), pair[1].clone()));
+                },
+                other => return Err(EvalError::syntax_error(format!("expected identifier in {} binding but found {:?}", form, other)).with_span(other.span()))
+            },
+            _ => return Err(EvalError::syntax_error(format!("{} bindings must be (variable init)", form)).with_span(binding.span()))
+        }
+    }
+
+    Ok(result)
+}
+
+/// Check the shape of the variable list of `do`, `((variable init [step]) ...)`
+fn do_bindings(bindings: &ParseTree) -> Result<Vec<(String, ParseTree, Option<ParseTree>)>, EvalError> {
+    let list = match bindings {
+        ParseTree::List(list, _) => list,
+        _ => return Err(EvalError::syntax_error("do expects a list of variables").with_span(bindings.span()))
+    };
+
+    let mut result = vec![];
+
+    for binding in list {
+        match binding {
+            ParseTree::List(parts, _) if parts.len() == 2 || parts.len() == 3 => match &parts[0] {
+                ParseTree::Element(Primitive::Identifier(variable), _) => {
+                    result.push((variable.clone(), parts[1].clone(), parts.get(2).cloned()));
+                },
+                other => return Err(EvalError::syntax_error(format!("expected identifier in do binding but found {:?}", other)).with_span(other.span()))
+            },
+            _ => return Err(EvalError::syntax_error("do bindings must be (variable init [step])").with_span(binding.span()))
+        }
+    }
+
+    Ok(result)
+}
+
+/// Whether `tree` is the identifier `keyword`, which special forms such as `case` use as markers
+fn is_keyword(tree: &ParseTree, keyword: &str) -> bool {
+    matches!(tree, ParseTree::Element(Primitive::Identifier(id), _) if syntax::base_name(id) == keyword)
+}
+
+/// An expression evaluating to `value`, for calling a procedure with an already evaluated argument
+fn quoted(value: Primitive, span: &Span) -> ParseTree {
+    let quote = ParseTree::Element(Primitive::Identifier(String::from("quote")), span.clone());
+    ParseTree::List(vec![quote, ParseTree::Element(value, span.clone())], span.clone())
+}
+
+/// Bind `name` to a macro whose body runs in `scope` when a use of it is expanded
+fn define_macro(name: &ParseTree, signature: &[ParseTree], body: Vec<ParseTree>, scope: &Scope) -> Result<Primitive, EvalError> {
+    let name = match name {
+        ParseTree::Element(Primitive::Identifier(id), _) => id.clone(),
+        other => return Err(EvalError::syntax_error(format!("expected macro name but found {:?}", other)).with_span(other.span()))
+    };
+    let arguments = parameters(signature)?;
+
+    Ok(set_variable(name, Primitive::Macro(arguments, Rc::new(body), scope.clone()), scope))
+}
+
+/// Expand a use of a `defmacro` macro by calling it with the unevaluated forms of `list`
+fn expand_macro(arguments: &Parameters, body: &[ParseTree], closure: &Scope, list: &[ParseTree], span: &Span) -> Result<ParseTree, EvalError> {
+    let forms = list[1..].iter().map(|form| form.clone().into_datum()).collect();
+    let scope = bind_arguments(arguments, forms, closure)?;
+    let last = evaluate_leading(body, &scope)?;
+    let expansion = interpret(last, &scope, false)?;
+
+    ParseTree::from_datum(expansion, span)
+}
+
+/// Expand `form` once if it is a use of a macro
+fn expand_once(form: &ParseTree, scope: &Scope) -> Result<Option<ParseTree>, EvalError> {
+    if let ParseTree::List(list, span) = form {
+        if let Some(ParseTree::Element(Primitive::Identifier(name), _)) = list.first() {
+            match &syntax::lookup(scope, name) {
+                Some(Primitive::Syntax(rules)) => return rules.expand(list, span).map(Some),
+                Some(Primitive::Macro(arguments, body, closure)) => return expand_macro(arguments, body, closure, list, span).map(Some),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Build the data described by a quasiquote `template`, evaluating the expressions it unquotes
+///
+/// `depth` counts the enclosing quasiquotes, only unquotes at depth 1 are evaluated and deeper
+/// ones are kept as data.
+fn quasiquote(template: &ParseTree, depth: usize, scope: &Scope) -> Result<Primitive, EvalError> {
+    let list = match template {
+        ParseTree::List(list, _) => list,
+        ParseTree::Element(_, _) => return Ok(template.clone().into_datum())
+    };
+
+    match unquoted(template) {
+        Some(("unquote", expression)) if depth == 1 => return interpret(expression.clone(), scope, false),
+        Some(("unquote", expression)) => return Ok(wrap("unquote", quasiquote(expression, depth - 1, scope)?)),
+        Some(("quasiquote", expression)) => return Ok(wrap("quasiquote", quasiquote(expression, depth + 1, scope)?)),
+        _ => {}
+    }
+
+    let (list, tail) = ParseTree::split_dotted(list);
+    let mut items = vec![];
+
+    for item in list {
+        match unquoted(item) {
+            Some(("unquote-splicing", expression)) if depth == 1 => {
+                let mut rest = interpret(expression.clone(), scope, false)?;
+
+                while let Primitive::Pair(car, cdr) = &rest {
+                    items.push(car.as_ref().clone());
+                    let next = cdr.as_ref().clone();
+                    rest = next;
+                }
+
+                if rest != Primitive::Nil {
+                    return Err(EvalError::type_error(format!("unquote-splicing expects a list but found {:?}", rest)).with_span(item.span()));
+                }
+            },
+            Some(("unquote-splicing", expression)) => items.push(wrap("unquote-splicing", quasiquote(expression, depth - 1, scope)?)),
+            _ => items.push(quasiquote(item, depth, scope)?)
+        }
     }
 
-    return (results, new_scope);
+    let tail = match tail {
+        Some(tail) => quasiquote(tail, depth, scope)?,
+        None => Primitive::Nil
+    };
+
+    Ok(items.into_iter().rev().fold(tail, |tail, item| Primitive::cons(item, tail)))
 }
 
-fn set_variable(label: String, value: Primitive, mut scope: Scope) -> (Primitive, Scope) {
-    scope.variables.insert(label.clone(), value);
-    return (Primitive::Identifier(label), scope);
+/// Split `(unquote x)`, `(unquote-splicing x)` and `(quasiquote x)` into the form's name and `x`
+fn unquoted(tree: &ParseTree) -> Option<(&str, &ParseTree)> {
+    match tree {
+        ParseTree::List(list, _) if list.len() == 2 => match &list[0] {
+            ParseTree::Element(Primitive::Identifier(name), _) => {
+                let name = syntax::base_name(name);
+
+                match name {
+                    "unquote" | "unquote-splicing" | "quasiquote" => Some((name, &list[1])),
+                    _ => None
+                }
+            },
+            _ => None
+        },
+        _ => None
+    }
 }
 
-fn define_lambda(signature: Vec<ParseTree>, body: ParseTree, scope: Scope) -> Primitive {
-    let (list, _) = flatten_tree(signature, scope);
-    return Primitive::Lambda(list, Box::new(body));
+fn wrap(name: &str, datum: Primitive) -> Primitive {
+    Primitive::list(vec![Primitive::Identifier(String::from(name)), datum])
 }
 
-fn define_procedure(signature: Vec<ParseTree>, body: ParseTree, scope: Scope) -> (Primitive, Scope) {
-    let slice = &signature[..];
-    let name = &slice[0];
-    let formal_arguments: Vec<ParseTree> = slice[1..].into();
+/// Create the frame a lambda body runs in, on top of the captured `closure` scope
+fn bind_arguments(arguments: &Parameters, values: Vec<Primitive>, closure: &Scope) -> Result<Scope, EvalError> {
+    if !arguments.accepts(values.len()) {
+        let name = arguments.name.as_deref().map_or("procedure", syntax::base_name);
+        return Err(EvalError::arity_error(format!("{} expects {} but got {}", name, arguments.arity(), values.len())));
+    }
+
+    let local_scope = closure.extend();
+    let mut values = values.into_iter();
+
+    for name in &arguments.required {
+        local_scope.define(name.clone(), values.next().unwrap_or(Primitive::Nil));
+    }
+
+    for (name, default) in &arguments.optional {
+        let value = match (values.next(), default) {
+            (Some(value), _) => value,
+            (None, Some(default)) => interpret(default.clone(), &local_scope, false)?,
+            (None, None) => Primitive::Nil
+        };
+        local_scope.define(name.clone(), value);
+    }
+
+    if let Some(rest) = &arguments.rest {
+        local_scope.define(rest.clone(), Primitive::list(values.collect()));
+    }
 
-    let lambda = define_lambda(formal_arguments, body, scope.clone());
+    Ok(local_scope)
+}
 
-    if let ParseTree::Element(Primitive::Identifier(id)) = name {
-        return set_variable(id.clone(), lambda, scope);
-    } else {
-        panic!("expected unknown type");
+/// Bind `values` to the parameters of a lambda, or of the first `case-lambda` clause accepting
+/// them, returning the new scope and the body to evaluate in it
+fn enter(procedure: Primitive, values: Vec<Primitive>) -> Result<(Scope, Rc<Vec<ParseTree>>), EvalError> {
+    match &procedure {
+        Primitive::Lambda(arguments, body, closure) => Ok((bind_arguments(arguments, values, closure)?, Rc::clone(body))),
+        Primitive::CaseLambda(clauses, closure) => {
+            match clauses.iter().find(|(arguments, _)| arguments.accepts(values.len())) {
+                Some((arguments, body)) => Ok((bind_arguments(arguments, values, closure)?, Rc::clone(body))),
+                None => Err(EvalError::arity_error(format!("no case-lambda clause accepts {} arguments", values.len())))
+            }
+        },
+        other => Err(EvalError::type_error(format!("cannot call {:?}", other)))
     }
 }
 
-fn apply(arguments: Vec<Primitive>, values: Vec<Primitive>, body: ParseTree, scope: Scope) -> (Primitive, Scope) {
-    let mut local_scope = scope.clone();
+/// What a native procedure can see of the evaluation that called it
+pub struct Context<'a> {
+    scope: &'a Scope
+}
+
+impl Context<'_> {
+    /// Call `procedure`, for example one passed in as an argument, with `arguments`
+    pub fn call(&self, procedure: Primitive, arguments: Vec<Primitive>) -> Result<Primitive, EvalError> {
+        call(procedure, arguments, self.scope)
+    }
+}
 
-    for (i, id) in arguments.into_iter().enumerate() {
-        if let Primitive::Identifier(varname) = id {
-            local_scope.variables.insert(varname, values[i].clone());
+/// Call `procedure` with `values` from outside the evaluation loop, running its body to the end
+///
+/// Native procedures see `scope` as the scope they were called from.
+pub fn call(procedure: Primitive, values: Vec<Primitive>, scope: &Scope) -> Result<Primitive, EvalError> {
+    match apply(procedure, values, scope)? {
+        Application::Value(result) => Ok(result),
+        Application::Tail(scope, body) => {
+            let last = evaluate_leading(&body, &scope)?;
+            interpret(last, &scope, false)
         }
     }
+}
+
+/// The outcome of applying a procedure: natives produce their result directly, lambdas leave a body
+/// to be evaluated in tail position
+enum Application {
+    Value(Primitive),
+    Tail(Scope, Rc<Vec<ParseTree>>)
+}
 
-    let (result, _) = interpret(body, local_scope, false);
-    
-    return (result, scope);
+fn apply(procedure: Primitive, values: Vec<Primitive>, scope: &Scope) -> Result<Application, EvalError> {
+    match &procedure {
+        Primitive::Function(function) => Ok(Application::Value(function(&Context { scope }, values)?)),
+        _ => {
+            let (local_scope, body) = enter(procedure, values)?;
+            Ok(Application::Tail(local_scope, body))
+        }
+    }
 }
 
-fn falsy(v: &Primitive) -> bool {
-    v == &Primitive::Bool(false) ||
-    v == &Primitive::Integer(0) ||
-    v == &Primitive::Nil
+/// Split the arguments of `apply`, `(f a b (c d))`, into the procedure and the arguments it is
+/// called with, `(a b c d)`
+pub fn unapply(mut values: Vec<Primitive>) -> Result<(Primitive, Vec<Primitive>), EvalError> {
+    if values.len() < 2 {
+        return Err(EvalError::arity_error(format!("apply expects at least 2 arguments but got {}", values.len())));
+    }
+
+    let procedure = values.remove(0);
+    let mut rest = values.pop().unwrap_or(Primitive::Nil);
+
+    while let Primitive::Pair(car, cdr) = &rest {
+        values.push(car.as_ref().clone());
+        let next = cdr.as_ref().clone();
+        rest = next;
+    }
+
+    if rest != Primitive::Nil {
+        return Err(EvalError::type_error(format!("apply expects a list of arguments but found {:?}", rest)));
+    }
+
+    Ok((procedure, values))
 }
 
-fn truthy(v: &Primitive) -> bool {
-    !falsy(v)
+/// Evaluate every expression of a body except the last, which is returned so the caller can
+/// evaluate it in tail position
+fn evaluate_leading(body: &[ParseTree], scope: &Scope) -> Result<ParseTree, EvalError> {
+    let (last, leading) = match body.split_last() {
+        Some(split) => split,
+        None => return Ok(ParseTree::List(vec![], Span::default()))
+    };
+
+    for expression in leading {
+        interpret(expression.clone(), scope, false)?;
+    }
+
+    Ok(last.clone())
 }
 
-#[cfg(test)] 
+
+#[cfg(test)]
 mod tests {
     use super::interpret;
-    use super::super::parser::parse;
+    use super::super::parser::{parse, parse_source};
     use super::super::env;
     use super::super::types::*;
+    use std::rc::Rc;
+    use super::super::error::{ErrorKind, EvalError, ParseErrorKind};
+
+    /// The data written as `source`
+    fn datum(source: &str) -> Primitive {
+        match parse(source).unwrap() {
+            ParseTree::List(mut forms, _) => forms.remove(0).into_datum(),
+            tree => tree.into_datum()
+        }
+    }
 
     #[test]
     fn test_case_and_empty() {
         let scope = env::standard_env();
-        let parse_tree = parse("(and)");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let parse_tree = parse("(and)").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Bool(true));
     }
 
     #[test]
     fn test_case_and_return_last_truthy() {
         let scope = env::standard_env();
-        let parse_tree = parse("(and 1 2 3 \"Eureka\")");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let parse_tree = parse("(and 1 2 3 \"Eureka\")").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::String(String::from("Eureka")));
     }
 
     #[test]
     fn test_case_and_return_false() {
-        let scope = env::standard_env();
-        let parse_tree = parse("(and 1 2 3 4 0)");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("(and 1 2 3 4 0)").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Bool(false));
     }
 
     #[test]
     fn test_case_or_empty() {
-        let scope = env::standard_env();
-        let parse_tree = parse("(or)");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("(or)").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Nil);
     }
 
     #[test]
     fn test_case_or_return_first_truthy() {
-        let scope = env::standard_env();
-        let parse_tree = parse("(or 0 0 1 0)");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("(or 0 0 1 0)").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(1));
     }
 
     #[test]
     fn test_case_or_return_nil() {
-        let scope = env::standard_env();
-        let parse_tree = parse("(or 0 0 0 0)");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("(or 0 0 0 0)").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Nil);
     }
 
@@ -294,9 +1048,9 @@ mod tests {
                       ((= x 0) 0)
                       ((< x 0) (- x))))
 
-            (abs -5)");
+            (abs -5)").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(5));
     }
 
@@ -304,19 +1058,19 @@ mod tests {
     fn test_else_case_analysis() {
         let scope = env::standard_env();
         let parse_tree = parse("
-            (cond (= 1 0)
+            (cond ((= 1 0) 0)
                   (else 123))
-        ");
+        ").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(123));
     }
 
     #[test]
     fn test_case_analysis_undefined() {
         let scope = env::standard_env();
-        let parse_tree = parse("(cond (= 1 2))");
-        let (result, _) = interpret(parse_tree, scope, true);
+        let parse_tree = parse("(cond ((= 1 2) 0))").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Nil);
     }
 
@@ -329,9 +1083,9 @@ mod tests {
                     (- x)
                     x))
             (abs -10)
-        ");
+        ").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(10));
     }
 
@@ -340,14 +1094,14 @@ mod tests {
         let scope = env::standard_env();
         let parse_tree = parse("
             (lambda (x) (* x x))
-        ");
+        ").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
-        let params = vec![Primitive::Identifier(String::from("x"))];
-        let body = parse("(* x x)");
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let params = Parameters::required(vec![String::from("x")]);
+        let body = parse("(* x x)").unwrap();
 
         let e1 = format!("{:?}", result);
-        let e2 = format!("{:?}", Primitive::Lambda(params, Box::new(body)));
+        let e2 = format!("{:?}", Primitive::Lambda(params, Rc::new(vec![body]), scope));
 
         assert_eq!(e1, e2);
     }
@@ -357,9 +1111,9 @@ mod tests {
         let scope = env::standard_env();
         let parse_tree = parse("
             ((lambda (x) (* x x)) 2)
-        ");
+        ").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(4));
     }
 
@@ -367,22 +1121,34 @@ mod tests {
     fn scope_local_and_global_test() {
         let scope = env::standard_env();
         let parse_tree = parse("
+            (define x 10)
             (define (foo x) x)
-            (define (bar x) ((foo 1) x))
-            (bar 5)
-        ");
+            (define (bar x) (+ (foo 1) x))
+            (list (bar 5) x)
+        ").unwrap();
 
-        let (result, _) = interpret(parse_tree, scope, true);
-        assert_eq!(result, Primitive::Integer(5));
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(6 10)"));
     }
 
     #[test]
     fn error_on_undefined_procedure() {
         let scope = env::standard_env();
-        let parse_tree = parse("(foobar 5)");
+        let parse_tree = parse("(foobar 5)").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnboundVariable);
+        assert_eq!(error.message, "foobar");
+    }
 
-        let (result, _) = interpret(parse_tree, scope, true);
-        assert_eq!(result, Primitive::String(String::from("undefined procedure \"foobar\"")));
+    #[test]
+    fn error_on_undefined_variable() {
+        for (source, name) in &[("(define y typo)", "typo"), ("(+ x 1)", "x"), ("(if missing 1 2)", "missing")] {
+            let scope = env::standard_env();
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::UnboundVariable, "{}", source);
+            assert_eq!(&error.message, name);
+        }
     }
 
     #[test]
@@ -396,9 +1162,886 @@ mod tests {
             ((lambda (x)
               (define _square_of_x (square x))
               (square _square_of_x)) 2)
-        ");
-    
-        let (result, _) = interpret(parse_tree, scope, true);
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
         assert_eq!(result, Primitive::Integer(16));
     }
+
+    #[test]
+    fn error_on_wrong_number_of_arguments() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (square x) (* x x))
+            (square 1 2)
+        ").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ArityError);
+    }
+
+    #[test]
+    fn error_on_malformed_lambda() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(lambda x)").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::SyntaxError);
+    }
+
+    #[test]
+    fn error_from_native_procedure() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(+ 1 \"two\")").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn closure_captures_defining_scope() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (make-adder n) (lambda (x) (+ x n)))
+            (define add5 (make-adder 5))
+            (define n 100)
+            (add5 1)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(6));
+    }
+
+    #[test]
+    fn closure_ignores_callers_bindings() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define y 1)
+            (define (get-y) y)
+            (define (shadow y) (get-y))
+            (shadow 2)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(1));
+    }
+
+    #[test]
+    fn closures_compose() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (compose f g) (lambda (x) (f (g x))))
+            (define (inc x) (+ x 1))
+            (define (double x) (* x 2))
+            ((compose inc double) 5)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(11));
+    }
+
+    #[test]
+    fn recursive_procedures_see_later_definitions() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (even? n) (if (= n 0) true (odd? (- n 1))))
+            (define (odd? n) (if (= n 0) false (even? (- n 1))))
+            (even? 10)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Bool(true));
+    }
+
+    #[test]
+    fn define_in_body_does_not_leak() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (f x)
+              (define local (* x 2))
+              local)
+            (f 2)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(4));
+        assert_eq!(scope.get("local"), None);
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_stack_space() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (loop n) (if (= n 0) 0 (loop (- n 1))))
+            (loop 100000)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(0));
+    }
+
+    #[test]
+    fn tail_calls_through_cond_and_or() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (count-down n)
+              (cond ((= n 0) true)
+                    (else (and true (or false (count-down (- n 1)))))))
+            (count-down 50000)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Bool(true));
+    }
+
+    #[test]
+    fn tail_call_from_last_body_expression() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (loop n acc)
+              (define next (- n 1))
+              (if (= n 0) acc (loop next (+ acc 1))))
+            (loop 50000 0)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(50000));
+    }
+
+    #[test]
+    fn quote_returns_data() {
+        let scope = env::standard_env();
+        let parse_tree = parse("'(1 (a \"b\"))").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![
+            Primitive::Integer(1),
+            Primitive::list(vec![Primitive::Identifier(String::from("a")), Primitive::String(String::from("b"))])
+        ]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn quote_does_not_evaluate() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 1)
+            (quote x)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Identifier(String::from("x")));
+    }
+
+    #[test]
+    fn quote_reads_dotted_pairs() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 3)
+            (list '(1 . 2) '(1 2 . 3) `(1 . ,x) (cdr '(a . b)) ((lambda (a . rest) rest) 1 2))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(datum("(1 . 2)"), Primitive::cons(Primitive::Integer(1), Primitive::Integer(2)));
+        assert_eq!(result, datum("((1 . 2) (1 2 . 3) (1 . 3) b (2))"));
+    }
+
+    #[test]
+    fn long_lists_are_compared_and_freed_without_recursion() {
+        let items = Primitive::list((0..400_000).map(Primitive::Integer).collect());
+        let mut other = Primitive::list((0..400_000).map(Primitive::Integer).collect());
+        assert_eq!(items, other);
+
+        other = Primitive::cons(Primitive::Integer(-1), other);
+        assert_ne!(items, other);
+
+        let scope = env::standard_env();
+        scope.define(String::from("items"), items);
+        let parse_tree = parse("(define items (cdr items)) (car items)").unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true), Ok(Primitive::Integer(1)));
+    }
+
+    #[test]
+    fn build_and_walk_lists() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (sum items)
+              (if (null? items)
+                  0
+                  (+ (car items) (sum (cdr items)))))
+            (sum (cons 1 (list 2 3 4)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(10));
+    }
+
+    #[test]
+    fn errors_report_source_location() {
+        let scope = env::standard_env();
+        let parse_tree = parse_source("
+(define (f x)
+  (* x \"two\"))
+(f 1)", "script.chibi").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        assert_eq!(format!("{}", error.span.unwrap()), "script.chibi:3:3");
+    }
+
+    #[test]
+    fn unbound_procedure_points_at_name() {
+        let scope = env::standard_env();
+        let parse_tree = parse_source("(+ 1 (foo 2))", "script.chibi").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(format!("{}", error), "script.chibi:1:7: unbound variable: foo\n    (+ 1 (foo 2))\n          ^");
+    }
+
+    #[test]
+    fn command_line_returns_arguments() {
+        let scope = env::standard_env();
+        env::set_command_line(&scope, vec![String::from("script.chibi"), String::from("a b")]);
+        let parse_tree = parse("(command-line)").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![Primitive::String(String::from("script.chibi")), Primitive::String(String::from("a b"))]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn quasiquote_unquotes_and_splices() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 2)
+            (define xs (list 3 4))
+            `(1 ,x ,@xs (nested ,(+ x 3)) ,@'())
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(1 2 3 4 (nested 5))"));
+    }
+
+    #[test]
+    fn nested_quasiquote_keeps_inner_unquotes() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 1)
+            `(a `(b ,(c ,x)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(a (quasiquote (b (unquote (c 1)))))"));
+    }
+
+    #[test]
+    fn defmacro_generates_code() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (defmacro my-unless (test then else)
+              `(if ,test ,else ,then))
+            (define-macro (swap-args call)
+              (cons (car call) (list (car (cdr (cdr call))) (car (cdr call)))))
+            (list (my-unless false 1 2) (swap-args (- 1 10)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(1), Primitive::Integer(9)]));
+    }
+
+    #[test]
+    fn macroexpand_shows_expansion() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (defmacro twice (x) `(double ,x))
+            (defmacro double (x) `(* 2 ,x))
+            (list (macroexpand-1 '(twice 3)) (macroexpand '(twice 3)) (macroexpand '(+ 1 2)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("((double 3) (* 2 3) (+ 1 2))"));
+    }
+
+    #[test]
+    fn set_changes_binding_shared_by_closures() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (make-counter)
+              (define count 0)
+              (list (lambda () (set! count (+ count 1)) count)
+                    (lambda () count)))
+            (define counter (make-counter))
+            (define increment (car counter))
+            (define current (car (cdr counter)))
+            (increment)
+            (increment)
+            (list (current) (increment))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(2), Primitive::Integer(3)]));
+    }
+
+    #[test]
+    fn set_changes_global_from_procedure() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define total 0)
+            (define (add! n) (set! total (+ total n)))
+            (add! 5)
+            (add! 7)
+            total
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(12));
+    }
+
+    #[test]
+    fn error_on_set_of_unbound_variable() {
+        let scope = env::standard_env();
+        let parse_tree = parse_source("(set! missing 1)", "script.chibi").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnboundVariable);
+        assert_eq!(error.message, "missing");
+        assert_eq!(format!("{}", error.span.unwrap()), "script.chibi:1:7");
+        assert_eq!(scope.get("missing"), None);
+    }
+
+    #[test]
+    fn let_evaluates_inits_in_enclosing_scope() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 1)
+            (let ((x 2) (y x))
+              (list x y))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(2), Primitive::Integer(1)]));
+        assert_eq!(scope.get("y"), None);
+    }
+
+    #[test]
+    fn let_star_binds_sequentially() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (let* ((x 1) (y (+ x 1)) (x (* y 10)))
+              (list x y))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(20), Primitive::Integer(2)]));
+    }
+
+    #[test]
+    fn letrec_allows_mutual_recursion() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (letrec ((even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+                     (odd? (lambda (n) (if (= n 0) false (even? (- n 1))))))
+              (even? 100))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Bool(true));
+    }
+
+    #[test]
+    fn letrec_star_sees_earlier_values() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (letrec* ((a 5) (b (* a 2)))
+              b)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(10));
+    }
+
+    #[test]
+    fn named_let_loops_in_constant_stack_space() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (let loop ((i 0) (acc 0))
+              (if (= i 50000)
+                  acc
+                  (loop (+ i 1) (+ acc 2))))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(100000));
+        assert_eq!(scope.get("loop"), None);
+    }
+
+    #[test]
+    fn error_on_malformed_let_bindings() {
+        for source in &["(let ((x)) x)", "(let (x 1) x)", "(let ((1 2)) 1)", "(let ((x 1) (x 2)) x)", "(letrec x 1)", "(let ((x 1)))"] {
+            let scope = env::standard_env();
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::SyntaxError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn begin_sequences_and_splices_definitions() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (begin
+              (define a 1)
+              (define b 2))
+            (list (begin) (begin a b))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Nil, Primitive::Integer(2)]));
+    }
+
+    #[test]
+    fn when_and_unless() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define log '())
+            (when (> 2 1) (set! log (cons 'when log)) 'ran)
+            (unless (> 2 1) (set! log (cons 'unless log)))
+            (list log (when false 1) (unless false 1 2))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("((when) () 2)"));
+    }
+
+    #[test]
+    fn case_selects_clause_by_datum() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (classify x)
+              (case x
+                ((1 2 3) 'small)
+                ((a b) 'letter)
+                ((10) => (lambda (n) (* n n)))
+                (else 'other)))
+            (list (classify 2) (classify 'b) (classify 10) (classify \"x\"))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(small letter 100 other)"));
+    }
+
+    #[test]
+    fn case_without_match_returns_nil() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(case 5 ((1) 'one))").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Nil);
+    }
+
+    #[test]
+    fn do_loops_with_steps_and_commands() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define visited '())
+            (define sum
+              (do ((i 0 (+ i 1))
+                   (acc 0 (+ acc i)))
+                  ((= i 5) acc)
+                (set! visited (cons i visited))))
+            (list sum visited)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(10 (4 3 2 1 0))"));
+    }
+
+    #[test]
+    fn error_on_malformed_case_and_do() {
+        for source in &["(case 1 (1 'one))", "(case 1 ((1)))", "(do ((i)) (true))", "(do ((i 0)) ())", "(when true)"] {
+            let scope = env::standard_env();
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::SyntaxError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn cond_clauses_follow_r7rs() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define log '())
+            (define (classify x)
+              (cond ((= x 0) (set! log (cons x log)) 'zero)
+                    ((car (list x)))
+                    (else 'unreachable)))
+            (list (classify 0) (classify 7) log
+                  (cond ((cdr (list 1 2)) => car) (else 'none))
+                  (cond ((= 1 2) 'no)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(zero 7 (0) 2 ())"));
+    }
+
+    #[test]
+    fn cond_else_is_syntactic() {
+        // else used to match only because it evaluated to the symbol else when unbound
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define else false)
+            (cond ((= 1 2) 'no) (else 'yes))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Identifier(String::from("yes")));
+    }
+
+    #[test]
+    fn error_on_malformed_cond() {
+        for source in &["(cond ())", "(cond (else 1) (true 2))", "(cond (else))", "(cond (true =>))", "(cond 1)"] {
+            let scope = env::standard_env();
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::SyntaxError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn only_false_is_false_by_default() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (list (if 0 'yes 'no)
+                  (if '() 'yes 'no)
+                  (cond (0 'yes) (else 'no))
+                  (and 1 0 2)
+                  (or 0 1)
+                  (or)
+                  (not 0)
+                  (not false)
+                  (when '() 'yes))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(yes yes yes 2 0 false false true yes)"));
+    }
+
+    #[test]
+    fn legacy_truthiness_treats_zero_and_nil_as_false() {
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("
+            (list (if 0 'yes 'no)
+                  (if '() 'yes 'no)
+                  (cond (0 'yes) (else 'no))
+                  (and 1 0 2)
+                  (or 0 1)
+                  (not 0)
+                  (unless '() 'yes))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(no no no false 1 true yes)"));
+    }
+
+    #[test]
+    fn legacy_and_or_not_give_the_earlier_results() {
+        let scope = env::standard_env_with(env::Truthiness::Legacy);
+        let parse_tree = parse("
+            (list (and) (and 1 2) (and 1 0) (and 1 '())
+                  (or) (or 0 0) (or 0 '()) (or 0 2 0)
+                  (not 0) (not '()) (not 1))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(true 2 false false () () () 2 true true false)"));
+    }
+
+    #[test]
+    fn rest_parameters_collect_extra_arguments() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (tagged tag . items) (cons tag items))
+            (define all (lambda args args))
+            (define (rest #!rest items) items)
+            (list (tagged 'a 1 2) (tagged 'b) (all) (all 1 2) (rest 3))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("((a 1 2) (b) () (1 2) (3))"));
+    }
+
+    #[test]
+    fn optional_parameters_use_defaults() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (range start #!optional (end (+ start 3)) step)
+              (list start end step))
+            (list (range 1) (range 1 10) (range 1 10 2))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("((1 4 ()) (1 10 ()) (1 10 2))"));
+    }
+
+    #[test]
+    fn case_lambda_dispatches_on_argument_count() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define area
+              (case-lambda
+                ((side) (* side side))
+                ((width height) (* width height))
+                ((width height . more) 'too-many)))
+            (list (area 3) (area 2 5) (area 1 2 3))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(9 10 too-many)"));
+
+        let error = interpret(parse("(area)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ArityError);
+    }
+
+    #[test]
+    fn arity_errors_describe_accepted_counts() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (one x) x)
+            (define (some x #!optional y) x)
+            (define (many x . rest) x)
+            (define (pair a b) a)
+        ").unwrap();
+        interpret(parse_tree, &scope, true).unwrap();
+
+        for (source, message) in &[
+            ("(one)", "one expects 1 argument but got 0"),
+            ("(one 1 2)", "one expects 1 argument but got 2"),
+            ("(some 1 2 3)", "some expects 1 to 2 arguments but got 3"),
+            ("(many)", "many expects at least 1 argument but got 0"),
+            ("(pair 1)", "pair expects 2 arguments but got 1"),
+            ("((lambda (x) x))", "procedure expects 1 argument but got 0"),
+            ("(let loop ((i 0)) (if (< i 1) (loop) i))", "loop expects 1 argument but got 0"),
+            ("(car)", "car expects 1 argument but got 0")
+        ] {
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::ArityError);
+            assert_eq!(&error.message, message);
+        }
+    }
+
+    #[test]
+    fn error_on_malformed_parameters() {
+        for source in &["(lambda (x x) x)", "(lambda ((x 1)) x)", "(case-lambda (x))", "(lambda (x #!rest) x)"] {
+            let scope = env::standard_env();
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::SyntaxError, "{}", source);
+        }
+
+        for source in &["(lambda (x . ) x)", "(lambda (x . y z) x)"] {
+            assert_eq!(parse(source).unwrap_err().kind, ParseErrorKind::MisplacedDot, "{}", source);
+        }
+    }
+
+    #[test]
+    fn defmacro_takes_rest_parameters() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (defmacro my-when (test . body) `(if ,test (begin ,@body) false))
+            (define log '())
+            (my-when true (set! log (cons 1 log)) (set! log (cons 2 log)))
+            log
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(2 1)"));
+    }
+
+    #[test]
+    fn builtins_are_first_class_values() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define plus +)
+            (define (twice f x) (f (f x 1) 1))
+            (list (plus 1 2) (twice + 1) ((if true * -) 3 4) (procedure? car) (procedure? (lambda () 1)) (procedure? 'car))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(3 3 12 true true false)"));
+    }
+
+    #[test]
+    fn map_accepts_builtins() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (import (only (chibi base) map))
+            (list (map + '(1 2) '(10 20 30)) (map car '((a b) (c d))) (map (lambda (x) (* x x)) '()))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("((11 22) (a c) ())"));
+    }
+
+    #[test]
+    fn map_handles_long_lists() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (import (only (chibi base) map reverse))
+            (define (count-down n items) (if (= n 0) items (count-down (- n 1) (cons n items))))
+            (define doubled (map (lambda (x) (* x 2)) (count-down 5000 '())))
+            (list (car doubled) (car (reverse doubled)) (reverse '(1 2 3)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(2 10000 (3 2 1))"));
+    }
+
+    #[test]
+    fn builtins_can_be_shadowed() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (f) (car '(1 2)))
+            (define (car x) 'mine)
+            (list (f) (let ((+ -)) (+ 5 2)) (+ 5 2)
+                  (let ((apply (lambda (f x) 'local))) (apply + '(1)))
+                  (apply + '(1)))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(mine 3 7 local 1)"));
+
+        let parse_tree = parse("(define (apply f x) 'mine) (apply + '(1))").unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap(), datum("mine"));
+    }
+
+    #[test]
+    fn apply_spreads_last_argument() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (count n) (if (= n 0) 'done (apply count (list (- n 1)))))
+            (define spread apply)
+            (list (apply + 1 '(2)) (apply list 1 2 '(3 4)) (apply (lambda args args) '()) ((lambda () 5)) (count 100000)
+                  (spread apply + '((1 2))) (procedure? apply))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(3 (1 2 3 4) () 5 done 3 true)"));
+
+        let error = interpret(parse("(apply + 1 2)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn deep_recursion_stops_at_the_limit() {
+        // test threads are too small for the limit, main.rs runs programs on a stack this size
+        let runner = std::thread::Builder::new().stack_size(1 << 30).spawn(|| {
+            let scope = env::standard_env();
+            let parse_tree = parse("
+                (define (forever) (+ 1 (forever)))
+                (define (count n) (if (= n 0) 0 (+ 1 (count (- n 1)))))
+                (count 2000)
+            ").unwrap();
+            assert_eq!(interpret(parse_tree, &scope, true), Ok(Primitive::Integer(2000)));
+
+            let error = interpret(parse("(forever)").unwrap(), &scope, true).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::RecursionLimit);
+            assert_eq!(interpret(parse("(count 10)").unwrap(), &scope, true), Ok(Primitive::Integer(10)));
+        });
+
+        runner.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn error_on_calling_non_procedure() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(define x 5) (x 1)").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        assert_eq!(error.span.unwrap().column, 15);
+
+        for source in &["(1 2 3)", "(\"abc\" 1)", "((list 1) 2)"] {
+            let error = interpret(parse(source).unwrap(), &scope, true).unwrap_err();
+            assert_eq!((error.kind, error.span.unwrap().column), (ErrorKind::TypeError, 2), "{}", source);
+        }
+    }
+
+    #[test]
+    fn print_returns_its_argument() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(define n 0) (print (begin (set! n (+ n 1)) n)) n").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(1));
+    }
+
+    #[test]
+    fn quit_unwinds_with_its_status() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(define (stop) (quit 3) 'unreachable) (stop)").unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap_err().kind, ErrorKind::Exit(3));
+
+        let parse_tree = parse("(quit)").unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap_err().kind, ErrorKind::Exit(0));
+
+        let parse_tree = parse("(quit \"now\")").unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap_err().kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn natives_capture_state_and_call_back() {
+        let scope = env::standard_env();
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let counter = Rc::clone(&calls);
+
+        scope.define_native("call-twice", move |context, mut arguments| {
+            counter.set(counter.get() + 1);
+
+            match arguments.len() {
+                2 => {
+                    let value = arguments.pop().unwrap();
+                    let procedure = arguments.pop().unwrap();
+                    let once = context.call(procedure.clone(), vec![value])?;
+                    context.call(procedure, vec![once])
+                },
+                count => Err(EvalError::arity_error(format!("call-twice expects 2 arguments but got {}", count)))
+            }
+        });
+
+        let parse_tree = parse("(list (call-twice (lambda (x) (* x 3)) 2) (call-twice car '((a))))").unwrap();
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(18 a)"));
+        assert_eq!(calls.get(), 2);
+
+        let error = interpret(parse("(call-twice 1)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ArityError);
+        assert_eq!(error.span.unwrap().column, 1);
+    }
+
+    #[test]
+    fn comparisons_mix_integers_and_floats() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(list (> 2 1.5) (> 1.5 2) (< 1 1.5) (= 2 2.0) (= 1 2))").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, datum("(true false true true false)"));
+
+        let error = interpret(parse("(< 1 'a)").unwrap(), &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        assert_eq!(error.message, "< argument 2: expected a number but found Identifier(a)");
+    }
+
+    #[test]
+    fn frames_of_finished_calls_are_freed() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define (sum-to n)
+              (define (add i total) (if (> i n) total (add (+ i 1) (+ total i))))
+              (add 0 0))
+            (define (repeat n) (if (> n 0) (begin (sum-to 10) (let loop ((i 0)) (if (< i 3) (loop (+ i 1)))) (repeat (- n 1)))))
+            (repeat 5000)
+        ").unwrap();
+
+        interpret(parse_tree, &scope, true).unwrap();
+        scope.collect_garbage();
+
+        let live = scope.frame_probes().iter().filter(|frame| frame.upgrade().is_some()).count();
+        assert!(live < 10, "{} frames still alive", live);
+    }
 }
@@ -0,0 +1,319 @@
+//! A little lisp interpreter that can be embedded in Rust programs
+//!
+//! ```
+//! use chibi::{Interpreter, Primitive};
+//!
+//! let interpreter = Interpreter::new();
+//! interpreter.define("limit", Primitive::Integer(10));
+//! interpreter.eval_str("(define (clamp x) (if (> x limit) limit x))").unwrap();
+//!
+//! let result = interpreter.call("clamp", vec![Primitive::Integer(42)]).unwrap();
+//! assert_eq!(result, Primitive::Integer(10));
+//! ```
+
+mod convert;
+mod env;
+mod error;
+mod types;
+mod parser;
+mod interpreter;
+mod loader;
+mod library;
+mod syntax;
+
+use env::Scope;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+pub use convert::{FromValue, IntoValue, TypedFunction};
+pub use env::Truthiness;
+pub use error::{EvalError, ErrorKind, ParseError, ParseErrorKind};
+pub use interpreter::Context;
+pub use types::{Primitive, Source, Span};
+
+/// How an `Interpreter` is set up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Load the standard library before any other code
+    pub stdlib: bool,
+    /// Which values conditionals treat as false
+    pub truthiness: Truthiness
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { stdlib: true, truthiness: Truthiness::Scheme }
+    }
+}
+
+/// A chibi environment that host code can evaluate programs in and exchange values with
+///
+/// Definitions persist between calls, so an interpreter behaves like a REPL session. Programs
+/// that call `(quit)` stop with an `ErrorKind::Exit` error instead of ending the process, and
+/// the `print` and `binding` forms write straight to stdout.
+///
+/// Tail calls run in constant stack space, other calls nest at most 10000 evaluations deep before
+/// failing with `ErrorKind::RecursionLimit`. Reaching that depth takes more stack than a thread
+/// gets by default, so hosts running deeply recursive programs should evaluate them on a thread
+/// with a large stack, as the `chibi` binary does.
+pub struct Interpreter {
+    scope: Scope
+}
+
+impl Interpreter {
+    /// An interpreter with the standard library loaded
+    pub fn new() -> Interpreter {
+        Interpreter::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Interpreter {
+        let interpreter = Interpreter { scope: env::standard_env_with(config.truthiness) };
+
+        if config.stdlib {
+            if let Err(error) = interpreter.eval_source(loader::STDLIB, "stdlib.chibi") {
+                panic!("failed to load stdlib: {}", error);
+            }
+        }
+
+        interpreter
+    }
+
+    /// Evaluate every form in `source` and return the value of the last one
+    pub fn eval_str(&self, source: &str) -> Result<Primitive, EvalError> {
+        self.eval_source(source, "<eval>")
+    }
+
+    /// Like `eval_str`, with errors pointing into `file`
+    ///
+    /// A leading `#!` interpreter line is ignored, so scripts can be passed in as they are.
+    pub fn eval_source(&self, source: &str, file: &str) -> Result<Primitive, EvalError> {
+        let parse_tree = parser::parse_source(&strip_shebang(source), file)?;
+        interpreter::interpret(parse_tree, &self.scope, true)
+    }
+
+    /// Read and evaluate the program in `path`
+    pub fn eval_file(&self, path: impl AsRef<Path>) -> Result<Primitive, EvalError> {
+        let path = path.as_ref();
+
+        match fs::read_to_string(path) {
+            Ok(source) => self.eval_source(&source, &path.to_string_lossy()),
+            Err(error) => Err(EvalError::load_error(format!("cannot read {}: {}", path.display(), error)))
+        }
+    }
+
+    /// Bind `name` to `value` in the global environment
+    pub fn define(&self, name: &str, value: Primitive) {
+        self.scope.define(name.to_string(), value);
+    }
+
+    /// The value `name` is bound to in the global environment, builtins included
+    pub fn get(&self, name: &str) -> Option<Primitive> {
+        self.scope.get(name)
+    }
+
+    /// Call the procedure bound to `name` with `arguments`
+    pub fn call(&self, name: &str, arguments: Vec<Primitive>) -> Result<Primitive, EvalError> {
+        match self.scope.get(name) {
+            Some(procedure) => self.apply(procedure, arguments),
+            None => Err(EvalError::unbound_variable(name))
+        }
+    }
+
+    /// Call `procedure`, a builtin or a value returned by chibi code, with `arguments`
+    pub fn apply(&self, procedure: Primitive, arguments: Vec<Primitive>) -> Result<Primitive, EvalError> {
+        interpreter::call(procedure, arguments, &self.scope)
+    }
+
+    /// Expose a Rust closure to chibi code as the procedure `name`
+    ///
+    /// ```
+    /// use chibi::{EvalError, Interpreter, Primitive};
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    ///
+    /// let interpreter = Interpreter::new();
+    /// let count = Rc::new(Cell::new(0));
+    /// let counter = Rc::clone(&count);
+    ///
+    /// interpreter.define_native("tick!", move |_, arguments| {
+    ///     if !arguments.is_empty() {
+    ///         return Err(EvalError::arity_error("tick! takes no arguments"));
+    ///     }
+    ///     counter.set(counter.get() + 1);
+    ///     Ok(Primitive::Integer(counter.get()))
+    /// });
+    ///
+    /// interpreter.eval_str("(tick!) (tick!)").unwrap();
+    /// assert_eq!(count.get(), 2);
+    /// ```
+    pub fn define_native<F>(&self, name: &str, function: F)
+    where F: Fn(&Context, Vec<Primitive>) -> Result<Primitive, EvalError> + 'static {
+        self.scope.define_native(name, function);
+    }
+
+    /// Expose a typed Rust function to chibi code as the procedure `name`
+    ///
+    /// Arguments are converted with `FromValue` and the result with `IntoValue`, so the function
+    /// never sees a value of the wrong type.
+    ///
+    /// ```
+    /// use chibi::{Interpreter, Primitive};
+    ///
+    /// let interpreter = Interpreter::new();
+    /// interpreter.define_function("repeat", |text: String, times: usize| text.repeat(times));
+    ///
+    /// let result = interpreter.eval_str("(repeat \"ab\" 3)").unwrap();
+    /// assert_eq!(result, Primitive::String(String::from("ababab")));
+    /// assert!(interpreter.eval_str("(repeat \"ab\" -1)").is_err());
+    /// ```
+    pub fn define_function<Args, F>(&self, name: &str, function: F)
+    where F: TypedFunction<Args> + 'static {
+        self.scope.define(name.to_string(), Primitive::Function(convert::function(name, function)));
+    }
+
+    /// Read the value bound to `name` as a Rust value
+    pub fn get_as<T: FromValue>(&self, name: &str) -> Result<T, EvalError> {
+        match self.scope.get(name) {
+            Some(value) => T::from_value(value),
+            None => Err(EvalError::unbound_variable(name))
+        }
+    }
+
+    /// Make `arguments` available to programs as the list of strings returned by `(command-line)`
+    pub fn set_command_line(&self, arguments: Vec<String>) {
+        env::set_command_line(&self.scope, arguments);
+    }
+}
+
+impl Drop for Interpreter {
+    /// Free the global frame and everything only it kept alive, which reference counting alone
+    /// cannot do because the procedures defined in it refer back to it
+    fn drop(&mut self) {
+        let libraries = mem::take(&mut *self.scope.libraries().borrow_mut());
+        drop(libraries);
+
+        let placeholder = self.scope.fresh();
+        drop(mem::replace(&mut self.scope, placeholder));
+        self.scope.collect_garbage();
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+/// Look up `name` in the working directory and then in each directory of `CHIBI_PATH`, also
+/// trying it with the `.chibi` extension
+pub fn find_file(name: &str) -> Option<PathBuf> {
+    loader::find(name, &loader::search_path())
+}
+
+/// Whether `source` stops in the middle of a form, so a REPL should read more lines before
+/// evaluating it
+pub fn is_incomplete(source: &str) -> bool {
+    match parser::parse_source(source, "<input>") {
+        Err(error) => error.is_incomplete(),
+        Ok(_) => false
+    }
+}
+
+/// Blank out a `#!/usr/bin/env chibi` line, keeping the line break so positions stay the same
+fn strip_shebang(source: &str) -> String {
+    if !source.starts_with("#!") {
+        return source.to_string();
+    }
+
+    match source.find('\n') {
+        Some(end) => source[end..].to_string(),
+        None => String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_shebang_line() {
+        assert_eq!(strip_shebang("#!/usr/bin/env chibi\n(+ 1 2)"), "\n(+ 1 2)");
+        assert_eq!(strip_shebang("(+ 1 2)"), "(+ 1 2)");
+    }
+
+    #[test]
+    fn detects_incomplete_input() {
+        assert!(is_incomplete("(define (f x)\n"));
+        assert!(is_incomplete("(display \"abc"));
+        assert!(!is_incomplete("(+ 1 2)"));
+        assert!(!is_incomplete("(+ 1 2))"));
+    }
+
+    #[test]
+    fn definitions_persist_between_evaluations() {
+        let interpreter = Interpreter::new();
+        interpreter.eval_str("(define x 2)").unwrap();
+
+        assert_eq!(interpreter.eval_str("(square x)").unwrap(), Primitive::Integer(4));
+        assert_eq!(interpreter.get("x"), Some(Primitive::Integer(2)));
+        assert_eq!(interpreter.get_as::<f64>("x"), Ok(2.0));
+        assert_eq!(interpreter.get_as::<String>("x").unwrap_err().kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn calls_procedures_with_host_values() {
+        let interpreter = Interpreter::new();
+        interpreter.define("greeting", Primitive::String(String::from("hi")));
+        interpreter.eval_str("(define (pair-with-greeting x) (cons greeting x))").unwrap();
+
+        let result = interpreter.call("pair-with-greeting", vec![Primitive::Integer(1)]).unwrap();
+        assert_eq!(result, Primitive::cons(Primitive::String(String::from("hi")), Primitive::Integer(1)));
+
+        let adder = interpreter.eval_str("(lambda (x . rest) (apply + x rest))").unwrap();
+        assert_eq!(interpreter.apply(adder, vec![Primitive::Integer(1), Primitive::Integer(2)]), Ok(Primitive::Integer(3)));
+        assert_eq!(interpreter.call("+", vec![Primitive::Integer(1), Primitive::Integer(2)]), Ok(Primitive::Integer(3)));
+
+        assert_eq!(interpreter.call("missing", vec![]).unwrap_err().kind, ErrorKind::UnboundVariable);
+        assert_eq!(interpreter.apply(Primitive::Integer(1), vec![]).unwrap_err().kind, ErrorKind::TypeError);
+    }
+
+    #[test]
+    fn config_controls_stdlib_and_truthiness() {
+        let interpreter = Interpreter::with_config(Config { stdlib: false, truthiness: Truthiness::Legacy });
+
+        assert_eq!(interpreter.get("square"), None);
+        assert_eq!(interpreter.eval_str("(if 0 'yes 'no)").unwrap(), Primitive::Identifier(String::from("no")));
+    }
+
+    #[test]
+    fn eval_file_reports_missing_files() {
+        let interpreter = Interpreter::new();
+        let error = interpreter.eval_file("/nonexistent/program.chibi").unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::LoadError);
+    }
+
+    #[test]
+    fn dropping_the_interpreter_frees_its_frames() {
+        let interpreter = Interpreter::new();
+        interpreter.eval_str("
+            (define (make-counter)
+              (define count 0)
+              (lambda () (set! count (+ count 1)) count))
+            (define counter (make-counter))
+            (counter)
+            (define-syntax twice (syntax-rules () ((_ e) (begin e e))))
+            (twice (counter))
+            (define-library (numbers) (export one) (begin (define (one) 1)))
+            (import (numbers))
+            (let loop ((i 0)) (if (< i 10) (loop (+ i 1)) (sqrt 16)))
+        ").unwrap();
+
+        let frames = interpreter.scope.frame_probes();
+        drop(interpreter);
+
+        assert!(frames.len() > 10);
+        assert!(frames.iter().all(|frame| frame.upgrade().is_none()));
+    }
+}
@@ -0,0 +1,470 @@
+use super::types::*;
+use super::env::Scope;
+use super::error::EvalError;
+use super::interpreter::interpret;
+use super::parser::parse_source;
+use super::loader;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The bindings a library makes available to its importers, under their exported names
+pub type Exports = Rc<Vec<(String, Primitive)>>;
+
+/// Libraries known to an interpreter, shared by every scope derived from its global scope
+///
+/// Each library is evaluated once, the first time it is defined or imported, and its exports are
+/// cached under its name. `loading` holds the libraries whose files are being evaluated, innermost
+/// last, to detect libraries that import each other. `roots` maps the files libraries were loaded
+/// from to the directory their names are relative to, where their own imports are looked up.
+#[derive(Default)]
+pub struct Libraries {
+    defined: HashMap<String, Exports>,
+    loading: Vec<String>,
+    roots: HashMap<String, PathBuf>
+}
+
+/// `(load "file")`: evaluate a file in `scope`, returning the value of its last form
+///
+/// The file is looked up next to the source of the `load` form first, then on the search path.
+pub fn load(file: &ParseTree, scope: &Scope) -> Result<Primitive, EvalError> {
+    let name = match &interpret(file.clone(), scope, false)? {
+        Primitive::String(name) => name.clone(),
+        other => return Err(EvalError::type_error(format!("load expects a file name but found {:?}", other)))
+    };
+
+    let path = find(&name, file.span())?;
+    run_file(&path, scope)
+}
+
+/// `(define-library (name ...) declaration ...)`: evaluate a library in its own environment and
+/// record its exports
+///
+/// The library starts out with only the builtins, anything else has to be imported.
+pub fn define_library(name: &ParseTree, declarations: &[ParseTree], scope: &Scope) -> Result<Primitive, EvalError> {
+    let name = library_name(name)?;
+    let environment = scope.fresh();
+    let mut exports = vec![];
+
+    for declaration in declarations {
+        let (keyword, arguments) = match declaration {
+            ParseTree::List(list, _) => match list.split_first() {
+                Some((ParseTree::Element(Primitive::Identifier(keyword), _), arguments)) => (keyword.as_str(), arguments),
+                _ => return Err(EvalError::syntax_error("malformed library declaration").with_span(declaration.span()))
+            },
+            _ => return Err(EvalError::syntax_error("malformed library declaration").with_span(declaration.span()))
+        };
+
+        match keyword {
+            "export" => {
+                for specification in arguments {
+                    exports.push(export_specification(specification)?);
+                }
+            },
+            "import" => {
+                import(arguments, &environment)?;
+            },
+            "begin" => {
+                for expression in arguments {
+                    interpret(expression.clone(), &environment, false)?;
+                }
+            },
+            "include" => {
+                for file in arguments {
+                    match file {
+                        ParseTree::Element(Primitive::String(name), span) => run_file(&find(name, span)?, &environment)?,
+                        _ => return Err(EvalError::syntax_error("include expects file names").with_span(file.span()))
+                    };
+                }
+            },
+            _ => {
+                let error = EvalError::syntax_error(format!("unknown library declaration {}", keyword));
+                return Err(error.with_span(declaration.span()));
+            }
+        }
+    }
+
+    let mut bindings = vec![];
+
+    for (internal, external) in exports {
+        match environment.get(&internal) {
+            Some(value) => bindings.push((external, value)),
+            None => return Err(EvalError::load_error(format!("library {} exports {} but does not define it", name, internal)))
+        }
+    }
+
+    scope.libraries().borrow_mut().defined.insert(name.clone(), Rc::new(bindings));
+    Ok(Primitive::Identifier(name))
+}
+
+/// `(import import-set ...)`: bind the names exported by libraries in `scope`
+///
+/// An import set is a library name, optionally wrapped in `only`, `except`, `prefix` or `rename`.
+pub fn import(sets: &[ParseTree], scope: &Scope) -> Result<Primitive, EvalError> {
+    for set in sets {
+        for (name, value) in import_set(set, scope)? {
+            scope.define(name, value);
+        }
+    }
+
+    Ok(Primitive::Nil)
+}
+
+fn import_set(set: &ParseTree, scope: &Scope) -> Result<Vec<(String, Primitive)>, EvalError> {
+    let list = match set {
+        ParseTree::List(list, _) if list.len() >= 2 => list,
+        _ => return library(set, scope).map(|exports| exports.to_vec())
+    };
+
+    let modifier = match &list[0] {
+        ParseTree::Element(Primitive::Identifier(modifier), _) => modifier.as_str(),
+        _ => ""
+    };
+    let arguments = &list[2..];
+
+    match modifier {
+        "only" => {
+            let bindings = import_set(&list[1], scope)?;
+            let mut selected = vec![];
+
+            for argument in arguments {
+                let name = identifier(argument)?;
+
+                match bindings.iter().find(|(exported, _)| exported == &name) {
+                    Some(binding) => selected.push(binding.clone()),
+                    None => return Err(EvalError::load_error(format!("cannot import {}, it is not exported", name)).with_span(argument.span()))
+                }
+            }
+
+            Ok(selected)
+        },
+        "except" => {
+            let excluded = arguments.iter().map(identifier).collect::<Result<Vec<_>, _>>()?;
+            let bindings = import_set(&list[1], scope)?;
+
+            Ok(bindings.into_iter().filter(|(name, _)| !excluded.contains(name)).collect())
+        },
+        "prefix" => {
+            if arguments.len() != 1 {
+                return Err(EvalError::syntax_error("bad prefix import set").with_span(set.span()));
+            }
+
+            let prefix = identifier(&arguments[0])?;
+            let bindings = import_set(&list[1], scope)?;
+
+            Ok(bindings.into_iter().map(|(name, value)| (format!("{}{}", prefix, name), value)).collect())
+        },
+        "rename" => {
+            let mut renames = vec![];
+
+            for argument in arguments {
+                match argument {
+                    ParseTree::List(pair, _) if pair.len() == 2 => renames.push((identifier(&pair[0])?, identifier(&pair[1])?)),
+                    _ => return Err(EvalError::syntax_error("rename expects (from to) pairs").with_span(argument.span()))
+                }
+            }
+
+            let bindings = import_set(&list[1], scope)?;
+
+            Ok(bindings.into_iter().map(|(name, value)| {
+                match renames.iter().find(|(from, _)| from == &name) {
+                    Some((_, to)) => (to.clone(), value),
+                    None => (name, value)
+                }
+            }).collect())
+        },
+        _ => library(set, scope).map(|exports| exports.to_vec())
+    }
+}
+
+/// The exports of the library named by `name`, evaluating it first if it was not seen before
+fn library(name: &ParseTree, scope: &Scope) -> Result<Exports, EvalError> {
+    let key = library_name(name)?;
+
+    if let Some(exports) = scope.libraries().borrow().defined.get(&key) {
+        return Ok(Rc::clone(exports));
+    }
+
+    {
+        let mut libraries = scope.libraries().borrow_mut();
+
+        if let Some(start) = libraries.loading.iter().position(|loading| loading == &key) {
+            let mut chain = libraries.loading[start..].to_vec();
+            chain.push(key);
+            return Err(EvalError::load_error(format!("import cycle {}", chain.join(" -> "))).with_span(name.span()));
+        }
+
+        libraries.loading.push(key.clone());
+    }
+
+    let result = load_library(&key, name, scope);
+    scope.libraries().borrow_mut().loading.pop();
+    result?;
+
+    match scope.libraries().borrow().defined.get(&key) {
+        Some(exports) => Ok(Rc::clone(exports)),
+        None => Err(EvalError::load_error(format!("library {} was not defined by its file", key)).with_span(name.span()))
+    }
+}
+
+/// Evaluate the source of a library that is not defined yet
+///
+/// `(chibi base)` is the standard library, any other `(a b)` is looked up as `a/b.sld` or
+/// `a/b.chibi`, which should define it. Names are relative to the directory of the importing file,
+/// or to the root of the importing library when it was loaded from a file itself.
+fn load_library(key: &str, name: &ParseTree, scope: &Scope) -> Result<(), EvalError> {
+    if key == "(chibi base)" {
+        let environment = scope.fresh();
+        interpret(parse_source(loader::STDLIB, "stdlib.chibi")?, &environment, true)?;
+
+        let exports = Rc::new(environment.local_bindings());
+        scope.libraries().borrow_mut().defined.insert(key.to_string(), exports);
+        return Ok(());
+    }
+
+    let parts = key[1..key.len() - 1].split(' ').collect::<Vec<_>>();
+    let path = parts.join("/");
+
+    let root = scope.libraries().borrow().roots.get(&name.span().source.name).cloned();
+    let search_path = match root {
+        Some(root) => std::iter::once(root).chain(loader::search_path()).collect(),
+        None => search_path(name.span())
+    };
+
+    let file = loader::find(&format!("{}.sld", path), &search_path)
+        .or_else(|| loader::find(&path, &search_path))
+        .ok_or_else(|| EvalError::load_error(format!("cannot find library {}", key)).with_span(name.span()))?;
+
+    if let Some(root) = file.ancestors().nth(parts.len()) {
+        scope.libraries().borrow_mut().roots.insert(file.to_string_lossy().into_owned(), root.to_path_buf());
+    }
+
+    run_file(&file, &scope.fresh())?;
+    Ok(())
+}
+
+/// The canonical form of a library name such as `(srfi 1)`, used as its key in the cache
+fn library_name(name: &ParseTree) -> Result<String, EvalError> {
+    let parts = match name {
+        ParseTree::List(parts, _) if !parts.is_empty() => parts,
+        _ => return Err(EvalError::syntax_error(format!("expected library name but found {:?}", name)).with_span(name.span()))
+    };
+
+    let mut names = vec![];
+
+    for part in parts {
+        match part {
+            ParseTree::Element(Primitive::Identifier(part), _) => names.push(part.clone()),
+            ParseTree::Element(Primitive::Integer(part), _) if *part >= 0 => names.push(part.to_string()),
+            _ => return Err(EvalError::syntax_error(format!("invalid library name part {:?}", part)).with_span(part.span()))
+        }
+    }
+
+    Ok(format!("({})", names.join(" ")))
+}
+
+/// An export specification, either `name` or `(rename internal external)`
+fn export_specification(specification: &ParseTree) -> Result<(String, String), EvalError> {
+    match specification {
+        ParseTree::List(list, _) if list.len() == 3 && identifier(&list[0])? == "rename" => {
+            Ok((identifier(&list[1])?, identifier(&list[2])?))
+        },
+        _ => {
+            let name = identifier(specification)?;
+            Ok((name.clone(), name))
+        }
+    }
+}
+
+fn identifier(tree: &ParseTree) -> Result<String, EvalError> {
+    match tree {
+        ParseTree::Element(Primitive::Identifier(id), _) => Ok(id.clone()),
+        _ => Err(EvalError::syntax_error(format!("expected identifier but found {:?}", tree)).with_span(tree.span()))
+    }
+}
+
+/// Where to look for files named in code at `span`: next to its source file, then on the search path
+fn search_path(span: &Span) -> Vec<PathBuf> {
+    let mut directories = vec![];
+
+    if let Some(parent) = Path::new(&span.source.name).parent() {
+        directories.push(parent.to_path_buf());
+    }
+
+    directories.extend(loader::search_path());
+    directories
+}
+
+fn find(name: &str, span: &Span) -> Result<PathBuf, EvalError> {
+    loader::find(name, &search_path(span)).ok_or_else(|| EvalError::load_error(format!("cannot find {}", name)).with_span(span))
+}
+
+fn run_file(path: &Path, scope: &Scope) -> Result<Primitive, EvalError> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => return Err(EvalError::load_error(format!("cannot read {}: {}", path.display(), error)))
+    };
+
+    interpret(parse_source(&source, &path.to_string_lossy())?, scope, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::env;
+    use super::super::error::ErrorKind;
+    use super::super::interpreter::interpret;
+    use super::super::parser::{parse, parse_source};
+    use super::super::types::*;
+    use std::env as std_env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory = std_env::temp_dir().join(format!("chibi-{}-{}", name, std::process::id()));
+        fs::create_dir_all(directory.join("util")).unwrap();
+        directory
+    }
+
+    #[test]
+    fn imports_only_exported_names() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-library (counter)
+              (export next (rename secret hidden))
+              (begin
+                (define secret 42)
+                (define helper 1)
+                (define (next n) (+ n helper))))
+            (import (counter))
+            (list (next 1) hidden)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(2), Primitive::Integer(42)]));
+        let error = interpret(parse("helper").unwrap(), &scope, true).unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::UnboundVariable, "helper"));
+    }
+
+    #[test]
+    fn import_set_modifiers() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-library (numbers)
+              (export one two three)
+              (begin (define one 1) (define two 2) (define three 3)))
+            (import (prefix (except (numbers) three) n:)
+                    (rename (only (numbers) three) (three drei)))
+            (list n:one n:two drei)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![Primitive::Integer(1), Primitive::Integer(2), Primitive::Integer(3)]);
+        assert_eq!(result, expected);
+        let error = interpret(parse("three").unwrap(), &scope, true).unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::UnboundVariable, "three"));
+    }
+
+    #[test]
+    fn libraries_start_without_global_bindings() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define outside 1)
+            (define-library (isolated)
+              (export f)
+              (begin (define f outside)))
+            (import (isolated))
+            f
+        ").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::UnboundVariable, "outside"));
+
+        // nor the global procedures, which are only reachable through an import
+        let parse_tree = parse("
+            (define (outer) 1)
+            (define-library (calls-outer) (export g) (begin (define (g) (outer))))
+            (import (calls-outer))
+            (g)
+        ").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::UnboundVariable, "outer"));
+    }
+
+    #[test]
+    fn imports_standard_library() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (import (only (chibi base) square))
+            (square 3)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(9));
+    }
+
+    #[test]
+    fn loads_libraries_from_files_once() {
+        let directory = temp_dir("library");
+        fs::write(directory.join("util").join("math.sld"), "
+            (define-library (util math)
+              (import (chibi base))
+              (export cube)
+              (begin (define (cube x) (* x (square x)))))
+        ").unwrap();
+
+        let scope = env::standard_env();
+        let main = directory.join("main.chibi");
+        let parse_tree = parse_source("(import (util math)) (cube 2)", &main.to_string_lossy()).unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap(), Primitive::Integer(8));
+
+        // later imports are served from the cache
+        fs::remove_file(directory.join("util").join("math.sld")).unwrap();
+        let parse_tree = parse_source("(import (util math)) (cube 3)", &main.to_string_lossy()).unwrap();
+        assert_eq!(interpret(parse_tree, &scope, true).unwrap(), Primitive::Integer(27));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn error_on_import_cycle() {
+        let directory = temp_dir("cycle");
+        fs::write(directory.join("util").join("a.sld"), "(define-library (util a) (import (util b)))").unwrap();
+        fs::write(directory.join("util").join("b.sld"), "(define-library (util b) (import (util a)))").unwrap();
+
+        let scope = env::standard_env();
+        let main = directory.join("main.chibi");
+        let parse_tree = parse_source("(import (util a))", &main.to_string_lossy()).unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::LoadError);
+        assert_eq!(error.message, "import cycle (util a) -> (util b) -> (util a)");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn error_on_missing_library() {
+        let scope = env::standard_env();
+        let parse_tree = parse("(import (no such library))").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::LoadError);
+    }
+
+    #[test]
+    fn load_evaluates_in_current_scope() {
+        let directory = temp_dir("load");
+        fs::write(directory.join("util").join("helpers.chibi"), "(define (double x) (* x 2)) (double 1)").unwrap();
+
+        let scope = env::standard_env();
+        let main = directory.join("main.chibi");
+        let parse_tree = parse_source("(list (load \"util/helpers.chibi\") (double 4))", &main.to_string_lossy()).unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(2), Primitive::Integer(8)]));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The standard library, evaluated before user code unless disabled
+pub const STDLIB: &str = include_str!("stdlib.chibi");
+
+/// Directories to look for chibi source files in: the working directory followed by those in the
+/// `CHIBI_PATH` environment variable, which uses the platform's `PATH` format
+pub fn search_path() -> Vec<PathBuf> {
+    let mut directories = vec![PathBuf::from(".")];
+
+    if let Some(paths) = env::var_os("CHIBI_PATH") {
+        directories.extend(env::split_paths(&paths));
+    }
+
+    directories
+}
+
+/// Resolve `name` to a source file
+///
+/// Absolute names are used as given. Relative names are tried against each directory of
+/// `search_path` in order, with and without a `.chibi` extension.
+pub fn find(name: &str, search_path: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(name);
+
+    if path.is_absolute() {
+        return Some(path.to_path_buf()).filter(|path| path.is_file());
+    }
+
+    for directory in search_path {
+        let candidate = directory.join(path);
+
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+
+        let candidate = candidate.with_extension("chibi");
+
+        if path.extension().is_none() && candidate.is_file() {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn finds_files_on_search_path() {
+        let directory = env::temp_dir().join(format!("chibi-loader-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("prelude.chibi"), "(define x 1)").unwrap();
+
+        let search_path = vec![PathBuf::from("/nonexistent"), directory.clone()];
+
+        assert_eq!(find("prelude.chibi", &search_path), Some(directory.join("prelude.chibi")));
+        assert_eq!(find("prelude", &search_path), Some(directory.join("prelude.chibi")));
+        assert_eq!(find("missing", &search_path), None);
+        assert_eq!(find(&directory.join("prelude").to_string_lossy(), &search_path), None);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
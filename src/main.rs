@@ -1,45 +1,264 @@
-mod env;
-mod types;
-mod parser;
-mod interpreter;
+use std::io::{self, Write, BufRead, IsTerminal, Read};
+use std::process;
+use std::thread;
+use chibi::{Config, ErrorKind, EvalError, Interpreter, Truthiness};
 
-use std::fs;
-use std::io::{self, Write, BufRead};
-use parser::parse;
-use interpreter::interpret;
+const USAGE: &str = "\
+Usage: chibi [options] [file | -] [arguments...]
+
+Runs the program in file, or in stdin when it is piped or given as -, and
+starts an interactive session otherwise. The remaining arguments are available
+to the program through (command-line).
+
+Options:
+  -e, --eval EXPRESSION  evaluate EXPRESSION and print its result
+  -p, --prelude FILE     load FILE before the program, may be given more than once
+      --no-stdlib        do not load the standard library
+      --legacy-truthiness
+                         treat 0 and the empty list as false in conditionals,
+                         as earlier versions did, instead of only false
+  -h, --help             print this message
+
+Preludes are looked up in the working directory and then in each directory of
+the CHIBI_PATH environment variable. Files named by load and libraries named by
+import are looked up next to the file that names them first.";
+
+#[derive(Debug, PartialEq)]
+enum Program {
+    Repl,
+    Expression(String),
+    File(String),
+    Stdin
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    program: Program,
+    arguments: Vec<String>,
+    stdlib: bool,
+    preludes: Vec<String>,
+    truthiness: Truthiness
+}
+
+/// Stack for the thread running programs, enough for the interpreter's recursion limit even in
+/// debug builds. Only the part that deep recursion touches is actually allocated.
+const STACK_SIZE: usize = 1 << 30;
 
 fn main() {
-    // global lisp environment
-    let scope = env::standard_env();
-    let stdlib = require("./src/stdlib.chibi");
-    let (_, mut scope) = interpret(parse(&stdlib), scope, true);
+    let runner = thread::Builder::new().name(String::from("chibi")).stack_size(STACK_SIZE).spawn(run);
 
-    loop {
-        print!("> ");
-        let expression = read();
-        let (result, result_scope) = interpret(parse(&expression), scope, true);
-        scope = result_scope;
-        println!("{:?}", result);
+    match runner.map(|runner| runner.join()) {
+        Ok(Ok(())) => {},
+        Ok(Err(_)) => process::exit(101),
+        Err(error) => {
+            eprintln!("chibi: cannot start the interpreter: {}", error);
+            process::exit(1);
+        }
     }
 }
 
-fn require(path: &str) -> String {
-    match fs::read_to_string(&path) {
-        Ok(string) => string,
-        _ => panic!("cannot read path")
+fn run() {
+    let mut arguments = std::env::args();
+    let name = arguments.next().unwrap_or_else(|| String::from("chibi"));
+
+    let options = match parse_arguments(arguments.collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("chibi: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let interpreter = Interpreter::with_config(Config { stdlib: options.stdlib, truthiness: options.truthiness });
+
+    for prelude in &options.preludes {
+        let path = match chibi::find_file(prelude) {
+            Some(path) => path,
+            None => {
+                eprintln!("chibi: cannot find prelude {}", prelude);
+                process::exit(1);
+            }
+        };
+
+        if let Err(error) = interpreter.eval_file(&path) {
+            fail(error);
+        }
     }
+
+    let mut command_line = vec![name];
+
+    match &options.program {
+        Program::File(path) => command_line[0] = path.clone(),
+        Program::Repl if io::stdin().is_terminal() => {
+            interpreter.set_command_line(command_line);
+            return repl(&interpreter);
+        },
+        _ => {}
+    }
+
+    command_line.extend(options.arguments);
+    interpreter.set_command_line(command_line);
+
+    let result = match options.program {
+        Program::Expression(expression) => interpreter.eval_str(&expression).map(|result| println!("{:?}", result)),
+        Program::File(path) => interpreter.eval_file(&path).map(|_| ()),
+        Program::Repl | Program::Stdin => interpreter.eval_source(&read_stdin(), "<stdin>").map(|_| ())
+    };
+
+    if let Err(error) = result {
+        fail(error);
+    }
+}
+
+/// End the process for an error that stopped the program, quietly if it was a `(quit)`
+fn fail(error: EvalError) -> ! {
+    if let ErrorKind::Exit(code) = error.kind {
+        process::exit(code);
+    }
+
+    eprintln!("error: {}", error);
+    process::exit(1);
+}
+
+/// Split the command line into the program to run and the arguments passed on to it
+///
+/// Options are only recognized before the program, everything after it belongs to the program.
+fn parse_arguments(arguments: Vec<String>) -> Result<Options, String> {
+    let mut arguments = arguments.into_iter();
+    let mut stdlib = true;
+    let mut preludes = vec![];
+    let mut truthiness = Truthiness::Scheme;
+
+    let program = loop {
+        let argument = match arguments.next() {
+            Some(argument) => argument,
+            None => break Program::Repl
+        };
+
+        match argument.as_str() {
+            "-e" | "--eval" => match arguments.next() {
+                Some(expression) => break Program::Expression(expression),
+                None => return Err(format!("{} requires an expression", argument))
+            },
+            "-p" | "--prelude" => match arguments.next() {
+                Some(prelude) => preludes.push(prelude),
+                None => return Err(format!("{} requires a file", argument))
+            },
+            "--no-stdlib" => stdlib = false,
+            "--legacy-truthiness" => truthiness = Truthiness::Legacy,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "-" => break Program::Stdin,
+            "--" => match arguments.next() {
+                Some(path) => break Program::File(path),
+                None => break Program::Repl
+            },
+            option if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+            _ => break Program::File(argument)
+        }
+    };
+
+    Ok(Options { program, arguments: arguments.collect(), stdlib, preludes, truthiness })
 }
 
-fn read() -> String {
-    let _ = io::stdout().flush();
+fn repl(interpreter: &Interpreter) {
+    while let Some(expression) = read() {
+        match interpreter.eval_source(&expression, "<input>") {
+            Ok(result) => println!("{:?}", result),
+            Err(EvalError { kind: ErrorKind::Exit(code), .. }) => process::exit(code),
+            Err(error) => println!("error: {}", error)
+        }
+    }
+
+    println!();
+}
+
+fn read_stdin() -> String {
+    let mut source = String::new();
+
+    if let Err(error) = io::stdin().read_to_string(&mut source) {
+        eprintln!("chibi: cannot read stdin: {}", error);
+        process::exit(1);
+    }
+
+    source
+}
+
+/// Read an expression from stdin, prompting for continuation lines until its parens, strings and
+/// block comments are closed. Returns `None` at the end of input.
+fn read() -> Option<String> {
     let stdin = io::stdin();
-    let line = stdin.lock()
-        .lines()
-        .next()
-        .unwrap();
-
-    match line {
-        Ok(input) => input,
-        Err(error) => panic!(error)
+    let mut lines = stdin.lock().lines();
+    let mut input = String::new();
+
+    print!("> ");
+
+    loop {
+        let _ = io::stdout().flush();
+
+        match lines.next() {
+            Some(Ok(line)) => input.push_str(&line),
+            Some(Err(error)) => {
+                eprintln!("chibi: cannot read stdin: {}", error);
+                process::exit(1);
+            },
+            None if input.is_empty() => return None,
+            None => return Some(input)
+        }
+        input.push('\n');
+
+        if !chibi::is_incomplete(&input) {
+            return Some(input);
+        }
+        print!(".. ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(list: &[&str]) -> Vec<String> {
+        list.iter().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_script_and_arguments() {
+        let options = parse_arguments(arguments(&["script.chibi", "-e", "x"])).unwrap();
+        assert_eq!(options.program, Program::File(String::from("script.chibi")));
+        assert_eq!(options.arguments, arguments(&["-e", "x"]));
+    }
+
+    #[test]
+    fn parses_expression() {
+        let options = parse_arguments(arguments(&["-e", "(+ 1 2)", "a"])).unwrap();
+        assert_eq!(options.program, Program::Expression(String::from("(+ 1 2)")));
+        assert_eq!(options.arguments, arguments(&["a"]));
+    }
+
+    #[test]
+    fn parses_stdin_and_repl() {
+        assert_eq!(parse_arguments(arguments(&["-", "a"])).unwrap().program, Program::Stdin);
+        assert_eq!(parse_arguments(arguments(&[])).unwrap().program, Program::Repl);
+        assert!(parse_arguments(arguments(&["--bogus"])).is_err());
+        assert!(parse_arguments(arguments(&["-e"])).is_err());
+    }
+
+    #[test]
+    fn parses_prelude_options() {
+        let options = parse_arguments(arguments(&["--no-stdlib", "-p", "a", "--prelude", "b.chibi", "script.chibi"])).unwrap();
+        assert_eq!(options.program, Program::File(String::from("script.chibi")));
+        assert!(!options.stdlib);
+        assert_eq!(options.preludes, arguments(&["a", "b.chibi"]));
+        assert!(parse_arguments(arguments(&["--prelude"])).is_err());
+    }
+
+    #[test]
+    fn parses_truthiness_option() {
+        assert_eq!(parse_arguments(arguments(&[])).unwrap().truthiness, Truthiness::Scheme);
+        let options = parse_arguments(arguments(&["--legacy-truthiness", "script.chibi"])).unwrap();
+        assert_eq!(options.truthiness, Truthiness::Legacy);
     }
 }
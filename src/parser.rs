@@ -1,111 +1,562 @@
 use super::types::*;
+use super::error::{ParseError, ParseErrorKind};
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
+use std::vec;
 
-pub fn parse(expression: &str) -> ParseTree {
-    let mut tokens = tokenize(expression);
-    let root_node = ParseTree::List(Vec::new());
-    let parse_tree = parenthesize(&mut tokens, root_node);
-    return parse_tree;
+#[cfg(test)]
+pub fn parse(expression: &str) -> Result<ParseTree, ParseError> {
+    parse_source(expression, "<input>")
 }
 
-/// Take an input string and split on whitespace
+/// Parse `expression`, naming it `file` in the spans of the resulting tree
 ///
-/// Given (+ 1 1) it returns vec!['(', '+', '1', '1', ')']
-fn tokenize(expression: &str) -> Vec<String> {
-    let expression = expression 
-        .replace("(", " ( ")
-        .replace(")", " ) ")
-        .trim()
-        .to_string();
+/// The result is a list holding each top-level form of the source.
+pub fn parse_source(expression: &str, file: &str) -> Result<ParseTree, ParseError> {
+    let source = Rc::new(Source { name: file.to_string(), text: expression.to_string() });
+    let mut tokens = tokenize(expression, &source)?.into_iter().peekable();
+    let mut forms = vec![];
+
+    while let Some(datum) = read_datum(&mut tokens)? {
+        forms.push(datum);
+    }
+
+    if let Some((_, span)) = tokens.next() {
+        return Err(ParseError::new(ParseErrorKind::UnexpectedCloseParen, "unexpected ')'", &span));
+    }
+
+    Ok(ParseTree::List(forms, Span::new(&source, 1, 1)))
+}
+
+type Tokens = Peekable<vec::IntoIter<(Token, Span)>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    /// One of the `'`, `` ` ``, `,` and `,@` shorthands, holding the name of the form it stands for
+    Prefix(&'static str),
+    DatumComment,
+    String(String),
+    Atom(String)
+}
 
-    expression.split_whitespace().map(|s| s.to_string()).collect()
+/// Iterates over the characters of the input while keeping track of the current line and column
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize
 }
 
-fn parenthesize(input: &mut Vec<String>, node: ParseTree) -> ParseTree {
-    if input.len() == 0 {
-        return node
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
     }
+}
+
+impl<'a> Iterator for Cursor<'a> {
+    type Item = char;
 
-    let token = input.remove(0);
-   
-    if token == "(" {
-        let new_node = ParseTree::List(Vec::new());
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
 
-        if let ParseTree::List(mut list) = node {
-            list.push(parenthesize(input, new_node));
-            return parenthesize(input, ParseTree::List(list));
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
         } else {
-            panic!("expected ast node to be list but found {:?}", node);
+            self.column += 1;
         }
-    } else if token == ")" {
-        return node;
-    } else {
-        if let ParseTree::List(mut list) = node {
-            list.push(categorize(&token));
-            return parenthesize(input, ParseTree::List(list));
-        } else {
-            panic!("expected ast node to be list but found {:?}", node);
+
+        Some(ch)
+    }
+}
+
+/// Split an input string into tokens, dropping comments
+///
+/// Given (+ 1 "a b") it returns vec![Open, Atom("+"), Atom("1"), String("a b"), Close], each
+/// paired with the span it starts at.
+fn tokenize(expression: &str, source: &Rc<Source>) -> Result<Vec<(Token, Span)>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = Cursor { chars: expression.chars().peekable(), line: 1, column: 1 };
+
+    loop {
+        let span = Span::new(source, chars.line, chars.column);
+
+        let ch = match chars.next() {
+            Some(ch) => ch,
+            None => break
+        };
+
+        match ch {
+            '(' => tokens.push((Token::Open, span)),
+            ')' => tokens.push((Token::Close, span)),
+            '\'' => tokens.push((Token::Prefix("quote"), span)),
+            '`' => tokens.push((Token::Prefix("quasiquote"), span)),
+            ',' if chars.peek() == Some('@') => {
+                chars.next();
+                tokens.push((Token::Prefix("unquote-splicing"), span));
+            },
+            ',' => tokens.push((Token::Prefix("unquote"), span)),
+            '"' => {
+                let string = read_string(&mut chars, &span)?;
+                tokens.push((Token::String(string), span));
+            },
+            ';' => skip_line_comment(&mut chars),
+            '#' if chars.peek() == Some('|') => {
+                chars.next();
+                skip_block_comment(&mut chars, &span)?;
+            },
+            '#' if chars.peek() == Some(';') => {
+                chars.next();
+                tokens.push((Token::DatumComment, span));
+            },
+            ch if ch.is_whitespace() => {},
+            ch => {
+                let mut atom = ch.to_string();
+
+                while let Some(next) = chars.peek() {
+                    if is_delimiter(next) {
+                        break;
+                    }
+                    atom.push(next);
+                    chars.next();
+                }
+
+                tokens.push((Token::Atom(atom), span));
+            }
         }
     }
+
+    Ok(tokens)
 }
 
-fn categorize(token: &String) -> ParseTree {
-    let first_ch = token.chars().next().unwrap();
-    let last_ch = token.chars().last().unwrap();
+fn is_delimiter(ch: char) -> bool {
+    ch.is_whitespace() || ch == '(' || ch == ')' || ch == '"' || ch == '\'' || ch == '`' || ch == ',' || ch == ';'
+}
+
+fn skip_line_comment(chars: &mut Cursor) {
+    for ch in chars.by_ref() {
+        if ch == '\n' {
+            break;
+        }
+    }
+}
 
-    let value: Primitive;
+/// Skip the rest of a `#| ... |#` comment, which may contain nested block comments
+fn skip_block_comment(chars: &mut Cursor, start: &Span) -> Result<(), ParseError> {
+    let mut depth = 1;
 
-    if token.parse::<f64>().is_ok() {
-        value = if token.contains(".") {
-            Primitive::Float(token.parse().unwrap())
+    while let Some(ch) = chars.next() {
+        if ch == '|' && chars.peek() == Some('#') {
+            chars.next();
+            depth -= 1;
+
+            if depth == 0 {
+                return Ok(());
+            }
+        } else if ch == '#' && chars.peek() == Some('|') {
+            chars.next();
+            depth += 1;
+        }
+    }
+
+    Err(ParseError::new(ParseErrorKind::UnterminatedComment, "unterminated block comment", start))
+}
+
+/// Read the body of a string literal up to its closing quote, resolving escape sequences
+fn read_string(chars: &mut Cursor, start: &Span) -> Result<String, ParseError> {
+    let mut string = String::new();
+
+    loop {
+        let escape = Span::new(&start.source, chars.line, chars.column);
+
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some('r') => string.push('\r'),
+                Some('a') => string.push('\u{7}'),
+                Some('b') => string.push('\u{8}'),
+                Some(ch @ '\\') | Some(ch @ '"') | Some(ch @ '|') => string.push(ch),
+                Some('x') => string.push(read_hex_escape(chars, &escape)?),
+                Some(ch) => {
+                    let message = format!("unknown escape sequence \\{}", ch);
+                    return Err(ParseError::new(ParseErrorKind::InvalidEscape, message, &escape));
+                },
+                None => break
+            },
+            Some(ch) => string.push(ch),
+            None => break
+        }
+    }
+
+    Err(ParseError::new(ParseErrorKind::UnterminatedString, "unterminated string", start))
+}
+
+/// Read the `41;` part of a `\x41;` escape, leaving whatever follows a malformed escape unread
+fn read_hex_escape(chars: &mut Cursor, escape: &Span) -> Result<char, ParseError> {
+    let mut digits = String::new();
+
+    while let Some(ch) = chars.peek().filter(char::is_ascii_hexdigit) {
+        chars.next();
+        digits.push(ch);
+    }
+
+    let character = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32);
+
+    match character {
+        Some(character) if chars.peek() == Some(';') => {
+            chars.next();
+            Ok(character)
+        },
+        _ => {
+            let message = format!("invalid hex escape \\x{}, expected hex digits ending in ';'", digits);
+            Err(ParseError::new(ParseErrorKind::InvalidEscape, message, escape))
+        }
+    }
+}
+
+/// Read the next complete expression, or `None` if the input or the enclosing list ends first
+fn read_datum(tokens: &mut Tokens) -> Result<Option<ParseTree>, ParseError> {
+    if let Some((Token::Close, _)) | None = tokens.peek() {
+        return Ok(None);
+    }
+
+    match tokens.next() {
+        Some((Token::Open, span)) => read_list(tokens, span).map(Some),
+        Some((Token::Prefix(name), span)) => prefixed(tokens, name, span).map(Some),
+        Some((Token::DatumComment, span)) => {
+            expect_datum(tokens, &span)?;
+            read_datum(tokens)
+        },
+        Some((token, span)) => categorize(token, span).map(Some),
+        None => Ok(None)
+    }
+}
+
+/// Read the elements of a list up to and including its closing paren
+fn read_list(tokens: &mut Tokens, open: Span) -> Result<ParseTree, ParseError> {
+    let mut list = vec![];
+
+    while let Some(datum) = read_datum(tokens)? {
+        list.push(datum);
+    }
+
+    // `(a . b)` is kept as it was written, whoever reads the list decides what the dot means
+    let is_dot = |datum: &ParseTree| matches!(datum, ParseTree::Element(Primitive::Identifier(id), _) if id == ".");
+
+    if let Some((_, dot)) = list.iter().enumerate().find(|(index, datum)| is_dot(datum) && index + 2 != list.len()) {
+        return Err(ParseError::new(ParseErrorKind::MisplacedDot, "'.' must be followed by exactly one expression", dot.span()));
+    }
+
+    match tokens.next() {
+        Some((Token::Close, _)) => Ok(ParseTree::List(list, open)),
+        _ => Err(ParseError::new(ParseErrorKind::UnclosedList, "unclosed '('", &open))
+    }
+}
+
+/// Read the expression a prefix such as `'`, `,@` or `#;` applies to
+fn expect_datum(tokens: &mut Tokens, prefix: &Span) -> Result<ParseTree, ParseError> {
+    match read_datum(tokens)? {
+        Some(datum) => Ok(datum),
+        None => Err(ParseError::new(ParseErrorKind::MissingDatum, "expected an expression", prefix))
+    }
+}
+
+/// Expand shorthands such as `'datum` into the form they stand for, `(quote datum)`
+fn prefixed(tokens: &mut Tokens, name: &str, span: Span) -> Result<ParseTree, ParseError> {
+    let datum = expect_datum(tokens, &span)?;
+    let form = ParseTree::Element(Primitive::Identifier(String::from(name)), span.clone());
+
+    Ok(ParseTree::List(vec![form, datum], span))
+}
+
+fn categorize(token: Token, span: Span) -> Result<ParseTree, ParseError> {
+    let token = match token {
+        Token::String(string) => return Ok(ParseTree::Element(Primitive::String(string), span)),
+        Token::Atom(atom) => atom,
+        _ => unreachable!("categorize only receives strings and atoms")
+    };
+
+    let value = if looks_numeric(&token) {
+        // only literals written with a fraction or an exponent are floats, so an integer that is
+        // too big is an error rather than silently losing precision
+        let is_float = token.contains(['.', 'e', 'E']);
+        let number = if is_float {
+            token.parse().ok().map(Primitive::Float)
         } else {
-            Primitive::Integer(token.parse().unwrap())
+            token.parse().ok().map(Primitive::Integer)
+        };
+
+        match number {
+            Some(number) => number,
+            None => {
+                // the first character is a sign or a digit
+                let message = if !is_float && token[1..].chars().all(|ch| ch.is_ascii_digit()) {
+                    format!("integer literal {} does not fit in 32 bits", token)
+                } else {
+                    format!("invalid number literal {}", token)
+                };
+                return Err(ParseError::new(ParseErrorKind::InvalidNumber, message, &span));
+            }
         }
-    } else if first_ch == '"' && last_ch == '"' {
-        value = Primitive::String(token[1..token.len() - 1].to_string());
     } else if token == "true" {
-        value = Primitive::Bool(true);
+        Primitive::Bool(true)
     } else if token == "false" {
-        value = Primitive::Bool(false);
+        Primitive::Bool(false)
     } else {
-        value = Primitive::Identifier(token.to_string());
+        Primitive::Identifier(token)
     };
 
-    return ParseTree::Element(value);
+    Ok(ParseTree::Element(value, span))
+}
+
+/// Whether an atom starts like a number, e.g. `12`, `-3`, `.5` or `+.5`
+fn looks_numeric(token: &str) -> bool {
+    let unsigned = token.strip_prefix(|ch| ch == '+' || ch == '-').unwrap_or(token);
+    let digits = unsigned.strip_prefix('.').unwrap_or(unsigned);
+
+    digits.starts_with(|ch: char| ch.is_ascii_digit())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn element(primitive: Primitive) -> ParseTree {
+        ParseTree::Element(primitive, Span::default())
+    }
+
+    fn list(items: Vec<ParseTree>) -> ParseTree {
+        ParseTree::List(items, Span::default())
+    }
+
     #[test]
     fn parses_expressions() {
-        let parse_tree = ParseTree::List(
+        let parse_tree = list(
             vec![
-                ParseTree::List(
+                list(
                     vec![
-                        ParseTree::Element(Primitive::Identifier(String::from("+"))),
-                        ParseTree::Element(Primitive::Integer(1)),
-                        ParseTree::List(
+                        element(Primitive::Identifier(String::from("+"))),
+                        element(Primitive::Integer(1)),
+                        list(
                             vec![
-                                ParseTree::Element(Primitive::Identifier(String::from("-"))),
-                                ParseTree::Element(Primitive::Integer(1)),
-                                ParseTree::Element(Primitive::Integer(1))
+                                element(Primitive::Identifier(String::from("-"))),
+                                element(Primitive::Integer(1)),
+                                element(Primitive::Integer(1))
                             ]
                         )
                     ]
                 )
             ]
         );
-        assert_eq!(parse("(+ 1 (- 1 1))"), parse_tree);
+        assert_eq!(parse("(+ 1 (- 1 1))").unwrap(), parse_tree);
     }
 
     #[test]
     fn parses_primatives() {
-        assert_eq!(parse("123"),        ParseTree::List(vec![ParseTree::Element(Primitive::Integer(123))]));
-        assert_eq!(parse("\"string\""), ParseTree::List(vec![ParseTree::Element(Primitive::String(String::from("string")))]));
-        assert_eq!(parse("1.5"),        ParseTree::List(vec![ParseTree::Element(Primitive::Float(1.5))]));
-        assert_eq!(parse("hello"),      ParseTree::List(vec![ParseTree::Element(Primitive::Identifier(String::from("hello")))]));
-        assert_eq!(parse("true"),       ParseTree::List(vec![ParseTree::Element(Primitive::Bool(true))]));
-        assert_eq!(parse("false"),       ParseTree::List(vec![ParseTree::Element(Primitive::Bool(false))]));
+        assert_eq!(parse("123").unwrap(),        list(vec![element(Primitive::Integer(123))]));
+        assert_eq!(parse("\"string\"").unwrap(), list(vec![element(Primitive::String(String::from("string")))]));
+        assert_eq!(parse("1.5").unwrap(),        list(vec![element(Primitive::Float(1.5))]));
+        assert_eq!(parse("hello").unwrap(),      list(vec![element(Primitive::Identifier(String::from("hello")))]));
+        assert_eq!(parse("true").unwrap(),       list(vec![element(Primitive::Bool(true))]));
+        assert_eq!(parse("false").unwrap(),       list(vec![element(Primitive::Bool(false))]));
+    }
+
+    #[test]
+    fn parses_quote_shorthand() {
+        let quote = element(Primitive::Identifier(String::from("quote")));
+        let parse_tree = list(
+            vec![
+                list(
+                    vec![
+                        quote.clone(),
+                        list(
+                            vec![
+                                element(Primitive::Integer(1)),
+                                list(vec![quote, element(Primitive::Identifier(String::from("a")))])
+                            ]
+                        )
+                    ]
+                )
+            ]
+        );
+        assert_eq!(parse("'(1 'a)").unwrap(), parse_tree);
+    }
+
+    #[test]
+    fn parses_quasiquote_shorthands() {
+        let identifier = |name: &str| element(Primitive::Identifier(String::from(name)));
+        let parse_tree = list(vec![
+            list(vec![
+                identifier("quasiquote"),
+                list(vec![
+                    list(vec![identifier("unquote"), identifier("a")]),
+                    list(vec![identifier("unquote-splicing"), identifier("b")])
+                ])
+            ])
+        ]);
+        assert_eq!(parse("`(,a ,@b)").unwrap(), parse_tree);
+        assert_eq!(parse("`(,a,@b)").unwrap(), parse_tree);
+    }
+
+    #[test]
+    fn parses_strings_with_whitespace_and_parens() {
+        let expected = list(vec![
+            list(vec![
+                element(Primitive::Identifier(String::from("print"))),
+                element(Primitive::String(String::from("hello (big) world")))
+            ])
+        ]);
+        assert_eq!(parse("(print \"hello (big) world\")").unwrap(), expected);
+    }
+
+    #[test]
+    fn parses_string_escapes() {
+        let expected = list(vec![
+            element(Primitive::String(String::from("a\nb\tc \"d\" \\ A")))
+        ]);
+        assert_eq!(parse("\"a\\nb\\tc \\\"d\\\" \\\\ \\x41;\"").unwrap(), expected);
+    }
+
+    #[test]
+    fn error_on_malformed_hex_escapes() {
+        for source in ["\"\\xZZ;\"", "\"\\x41\"", "\"\\x;\"", "\"\\xD800;\""].iter() {
+            let error = parse(source).unwrap_err();
+            assert_eq!((error.kind, error.span.column), (ParseErrorKind::InvalidEscape, 2));
+        }
+    }
+
+    #[test]
+    fn error_on_unknown_escapes() {
+        let error = parse("(print \"a\\qb\")").unwrap_err();
+        assert_eq!((error.kind, error.span.column), (ParseErrorKind::InvalidEscape, 10));
+    }
+
+    #[test]
+    fn string_delimits_atoms() {
+        let expected = list(vec![
+            element(Primitive::Identifier(String::from("a"))),
+            element(Primitive::String(String::from("b"))),
+            element(Primitive::Identifier(String::from("c")))
+        ]);
+        assert_eq!(parse("a\"b\"c").unwrap(), expected);
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        let expected = list(vec![
+            element(Primitive::Integer(1)),
+            element(Primitive::String(String::from("; not a comment"))),
+            element(Primitive::Identifier(String::from("x")))
+        ]);
+        assert_eq!(parse("; note\n1 \"; not a comment\" x; trailing\n").unwrap(), expected);
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        let expected = list(vec![
+            element(Primitive::Integer(1)),
+            element(Primitive::Integer(2))
+        ]);
+        assert_eq!(parse("1 #| outer #| inner |# (still outer) |# 2").unwrap(), expected);
+    }
+
+    #[test]
+    fn skips_datum_comments() {
+        let expected = list(vec![
+            list(vec![
+                element(Primitive::Identifier(String::from("+"))),
+                element(Primitive::Integer(1)),
+                element(Primitive::Integer(3))
+            ])
+        ]);
+        assert_eq!(parse("(+ 1 #;(* 2 (f x)) 3 #;4)").unwrap(), expected);
+    }
+
+    #[test]
+    fn records_token_positions() {
+        let tree = parse_source("(define x\n  (+ 1 2))", "example.chibi").unwrap();
+
+        if let ParseTree::List(forms, _) = tree {
+            if let ParseTree::List(define, span) = &forms[0] {
+                assert_eq!(format!("{}", span), "example.chibi:1:1");
+                assert_eq!(format!("{}", define[1].span()), "example.chibi:1:9");
+                assert_eq!(format!("{}", define[2].span()), "example.chibi:2:3");
+                assert_eq!(define[2].span().snippet(), "  (+ 1 2))");
+                return;
+            }
+        }
+        panic!("unexpected parse tree");
+    }
+
+    #[test]
+    fn error_on_unclosed_list() {
+        let error = parse("(define (f x)\n  (+ x 1)").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnclosedList);
+        assert_eq!((error.span.line, error.span.column), (1, 1));
+    }
+
+    #[test]
+    fn error_on_stray_close_paren() {
+        let error = parse("(+ 1 2))").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedCloseParen);
+        assert_eq!((error.span.line, error.span.column), (1, 8));
+    }
+
+    #[test]
+    fn error_on_unterminated_string() {
+        let error = parse("(print \"hello)").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedString);
+        assert_eq!((error.span.line, error.span.column), (1, 8));
+    }
+
+    #[test]
+    fn error_on_unterminated_block_comment() {
+        let error = parse("1 #| never closed").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedComment);
+    }
+
+    #[test]
+    fn error_on_invalid_numbers() {
+        assert_eq!(parse("1.2.3").unwrap_err().kind, ParseErrorKind::InvalidNumber);
+        assert_eq!(parse("12abc").unwrap_err().kind, ParseErrorKind::InvalidNumber);
+        assert_eq!(parse("-").unwrap(), list(vec![element(Primitive::Identifier(String::from("-")))]));
+        assert_eq!(parse("1e3").unwrap(), list(vec![element(Primitive::Float(1000.0))]));
+    }
+
+    #[test]
+    fn error_on_integers_out_of_range() {
+        let error = parse("(+ 1 3000000000)").unwrap_err();
+        assert_eq!((error.kind, error.span.column), (ParseErrorKind::InvalidNumber, 6));
+        assert_eq!(parse("-2147483649").unwrap_err().kind, ParseErrorKind::InvalidNumber);
+        assert_eq!(parse("-2147483648").unwrap(), list(vec![element(Primitive::Integer(i32::MIN))]));
+        assert_eq!(parse("3000000000.0").unwrap(), list(vec![element(Primitive::Float(3e9))]));
+    }
+
+    #[test]
+    fn error_on_misplaced_dot() {
+        for source in ["(1 .)", "(1 . 2 3)", "(1 . . 2)", "(. 1 2)"].iter() {
+            assert_eq!(parse(source).unwrap_err().kind, ParseErrorKind::MisplacedDot, "{}", source);
+        }
+        assert!(parse("(lambda (. args) args)").is_ok());
+    }
+
+    #[test]
+    fn error_on_quote_without_datum() {
+        assert_eq!(parse("(list ')").unwrap_err().kind, ParseErrorKind::MissingDatum);
+        assert_eq!(parse("#;").unwrap_err().kind, ParseErrorKind::MissingDatum);
+        assert_eq!(parse("`(a ,@)").unwrap_err().kind, ParseErrorKind::MissingDatum);
+    }
+
+    #[test]
+    fn incomplete_input_can_be_continued() {
+        assert!(parse("(define (f x)").unwrap_err().is_incomplete());
+        assert!(parse("(print \"a (").unwrap_err().is_incomplete());
+        assert!(parse("#| (").unwrap_err().is_incomplete());
+        assert!(!parse("(f))").unwrap_err().is_incomplete());
+        assert!(parse("(f \")\" ; )\n)").is_ok());
     }
 }
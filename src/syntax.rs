@@ -0,0 +1,505 @@
+use super::types::*;
+use super::env::Scope;
+use super::error::EvalError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Separates an identifier introduced by a macro expansion from the number of that expansion
+///
+/// The reader never produces it, so renamed identifiers cannot clash with anything a user writes.
+const RENAME_MARKER: char = '\u{0}';
+
+/// Separates the number of an expansion from the number of the macro definition it expanded
+const DEFINITION_MARKER: char = ':';
+
+/// Number of macro expansions so far, used to give each expansion its own names
+static EXPANSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of macro definitions so far, used to find a macro from the names it introduced
+static DEFINITIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The macros defined so far that are still alive, by number
+    static MACROS: RefCell<HashMap<usize, Weak<SyntaxRules>>> = RefCell::new(HashMap::new());
+}
+
+/// A `syntax-rules` transformer
+///
+/// Expansion is hygienic in that every identifier a template introduces is renamed, so a binding
+/// made by the expansion can never capture an identifier from the macro use. Renamed identifiers
+/// that the expansion does not bind itself refer to what the original name refers to where the
+/// macro was defined, see `lookup`.
+pub struct SyntaxRules {
+    ellipsis: Option<String>,
+    literals: Vec<String>,
+    rules: Vec<(Vec<ParseTree>, ParseTree)>,
+    /// Where the macro was defined
+    scope: Scope,
+    definition: usize
+}
+
+/// What a pattern variable matched, with one level of nesting per ellipsis following it
+#[derive(Clone)]
+enum Binding {
+    One(ParseTree),
+    Many(Vec<Binding>)
+}
+
+type Bindings = HashMap<String, Binding>;
+
+impl SyntaxRules {
+    /// Read a `(syntax-rules (literal ...) (pattern template) ...)` transformer, which may name a
+    /// custom ellipsis identifier before the literals, for a macro defined in `scope`
+    pub fn parse(specification: &ParseTree, scope: &Scope) -> Result<Rc<SyntaxRules>, EvalError> {
+        let list = match specification {
+            ParseTree::List(list, _) if is_keyword(list.first(), "syntax-rules") => list,
+            _ => return Err(EvalError::syntax_error("expected a syntax-rules transformer").with_span(specification.span()))
+        };
+
+        let mut rest = &list[1..];
+        let mut ellipsis = String::from("...");
+
+        if let Some(ParseTree::Element(Primitive::Identifier(custom), _)) = rest.first() {
+            ellipsis = custom.clone();
+            rest = &rest[1..];
+        }
+
+        let literals = match rest.first() {
+            Some(ParseTree::List(literals, _)) => literals.iter().map(identifier).collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(EvalError::syntax_error("syntax-rules expects a list of literals").with_span(specification.span()))
+        };
+
+        let mut rules = vec![];
+
+        for rule in &rest[1..] {
+            match rule {
+                ParseTree::List(rule, _) if rule.len() == 2 => match &rule[0] {
+                    ParseTree::List(pattern, _) if !pattern.is_empty() => rules.push((pattern.clone(), rule[1].clone())),
+                    pattern => return Err(EvalError::syntax_error("syntax rule patterns must be lists").with_span(pattern.span()))
+                },
+                _ => return Err(EvalError::syntax_error("syntax rules must be (pattern template)").with_span(rule.span()))
+            }
+        }
+
+        // an ellipsis listed as a literal only matches itself
+        let ellipsis = Some(ellipsis).filter(|ellipsis| !literals.contains(ellipsis));
+
+        let definition = DEFINITIONS.fetch_add(1, Ordering::Relaxed);
+        let syntax_rules = Rc::new(SyntaxRules { ellipsis, literals, rules, scope: scope.clone(), definition });
+        MACROS.with(|macros| macros.borrow_mut().insert(definition, Rc::downgrade(&syntax_rules)));
+
+        Ok(syntax_rules)
+    }
+
+    /// The scope the macro was defined in
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Rewrite `form`, a use of the macro, with the template of the first rule matching it
+    pub fn expand(&self, form: &[ParseTree], span: &Span) -> Result<ParseTree, EvalError> {
+        for (pattern, template) in &self.rules {
+            let mut bindings = HashMap::new();
+
+            // the keyword position of the pattern is ignored
+            if self.match_list(&pattern[1..], &form[1..], &mut bindings) {
+                let expansion = format!("{}{}{}", EXPANSIONS.fetch_add(1, Ordering::Relaxed), DEFINITION_MARKER, self.definition);
+                return instantiate(template, &bindings, self.ellipsis.as_deref(), &expansion);
+            }
+        }
+
+        let name = match &form[0] {
+            ParseTree::Element(Primitive::Identifier(name), _) => base_name(name),
+            _ => "macro"
+        };
+        Err(EvalError::syntax_error(format!("no syntax rule matches this use of {}", name)).with_span(span))
+    }
+
+    fn match_pattern(&self, pattern: &ParseTree, form: &ParseTree, bindings: &mut Bindings) -> bool {
+        match pattern {
+            ParseTree::Element(Primitive::Identifier(id), _) if id == "_" => true,
+            ParseTree::Element(Primitive::Identifier(id), _) if self.literals.contains(id) => {
+                matches!(form, ParseTree::Element(Primitive::Identifier(name), _) if base_name(name) == base_name(id))
+            },
+            ParseTree::Element(Primitive::Identifier(id), _) => {
+                bindings.insert(id.clone(), Binding::One(form.clone()));
+                true
+            },
+            ParseTree::List(patterns, _) => match form {
+                ParseTree::List(forms, _) => self.match_list(patterns, forms, bindings),
+                _ => false
+            },
+            ParseTree::Element(datum, _) => matches!(form, ParseTree::Element(other, _) if other == datum)
+        }
+    }
+
+    /// Match a list of patterns, where the pattern before an ellipsis matches any number of forms
+    fn match_list(&self, patterns: &[ParseTree], forms: &[ParseTree], bindings: &mut Bindings) -> bool {
+        let position = match patterns.iter().position(|pattern| is_ellipsis(pattern, self.ellipsis.as_deref())) {
+            Some(position) if position > 0 => position,
+            Some(_) => return false,
+            None => {
+                return patterns.len() == forms.len() &&
+                    patterns.iter().zip(forms).all(|(pattern, form)| self.match_pattern(pattern, form, bindings));
+            }
+        };
+
+        let repeated = &patterns[position - 1];
+        let before = &patterns[..position - 1];
+        let after = &patterns[position + 1..];
+
+        if forms.len() < before.len() + after.len() {
+            return false;
+        }
+
+        let end = forms.len() - after.len();
+
+        if !self.match_list(before, &forms[..before.len()], bindings) || !self.match_list(after, &forms[end..], bindings) {
+            return false;
+        }
+
+        let mut matches = vec![];
+
+        for form in &forms[before.len()..end] {
+            let mut iteration = HashMap::new();
+
+            if !self.match_pattern(repeated, form, &mut iteration) {
+                return false;
+            }
+            matches.push(iteration);
+        }
+
+        for variable in self.pattern_variables(repeated) {
+            let sequence = matches.iter_mut().filter_map(|iteration| iteration.remove(&variable)).collect();
+            bindings.insert(variable, Binding::Many(sequence));
+        }
+
+        true
+    }
+
+    fn pattern_variables(&self, pattern: &ParseTree) -> Vec<String> {
+        match pattern {
+            ParseTree::Element(Primitive::Identifier(id), _) => {
+                if id == "_" || self.literals.contains(id) || self.ellipsis.as_ref() == Some(id) {
+                    vec![]
+                } else {
+                    vec![id.clone()]
+                }
+            },
+            ParseTree::List(patterns, _) => patterns.iter().flat_map(|pattern| self.pattern_variables(pattern)).collect(),
+            ParseTree::Element(_, _) => vec![]
+        }
+    }
+}
+
+/// Fill in `template` with the forms bound to pattern variables, renaming every other identifier
+/// for this `expansion`
+fn instantiate(template: &ParseTree, bindings: &Bindings, ellipsis: Option<&str>, expansion: &str) -> Result<ParseTree, EvalError> {
+    match template {
+        ParseTree::Element(Primitive::Identifier(id), span) => match bindings.get(id) {
+            Some(Binding::One(form)) => Ok(form.clone()),
+            Some(Binding::Many(_)) => {
+                Err(EvalError::syntax_error(format!("pattern variable {} must be followed by an ellipsis", id)).with_span(span))
+            },
+            None => Ok(ParseTree::Element(Primitive::Identifier(rename(id, expansion)), span.clone()))
+        },
+        ParseTree::List(templates, span) => {
+            // (... template) stands for template with the ellipsis taken literally
+            if templates.len() == 2 && is_ellipsis(&templates[0], ellipsis) {
+                return instantiate(&templates[1], bindings, None, expansion);
+            }
+
+            let mut items = vec![];
+            let mut index = 0;
+
+            while index < templates.len() {
+                let mut depth = 0;
+
+                while templates.get(index + depth + 1).is_some_and(|template| is_ellipsis(template, ellipsis)) {
+                    depth += 1;
+                }
+
+                if depth == 0 {
+                    items.push(instantiate(&templates[index], bindings, ellipsis, expansion)?);
+                } else {
+                    instantiate_repeated(&templates[index], depth, bindings, ellipsis, expansion, &mut items)?;
+                }
+
+                index += depth + 1;
+            }
+
+            Ok(ParseTree::List(items, span.clone()))
+        },
+        ParseTree::Element(_, _) => Ok(template.clone())
+    }
+}
+
+/// Instantiate a template followed by `depth` ellipses once per form its pattern variables matched
+fn instantiate_repeated(template: &ParseTree, depth: usize, bindings: &Bindings, ellipsis: Option<&str>,
+                        expansion: &str, items: &mut Vec<ParseTree>) -> Result<(), EvalError> {
+    let mut variables = vec![];
+    template_variables(template, &mut variables);
+
+    let sequences = variables.into_iter()
+        .filter_map(|variable| match bindings.get(&variable) {
+            Some(Binding::Many(sequence)) => Some((variable, sequence)),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+
+    let length = match sequences.first() {
+        Some((_, sequence)) => sequence.len(),
+        None => return Err(EvalError::syntax_error("ellipsis follows a template without repeated pattern variables").with_span(template.span()))
+    };
+
+    if sequences.iter().any(|(_, sequence)| sequence.len() != length) {
+        return Err(EvalError::syntax_error("pattern variables under the same ellipsis matched different numbers of forms").with_span(template.span()));
+    }
+
+    for index in 0..length {
+        let mut iteration = bindings.clone();
+
+        for (variable, sequence) in &sequences {
+            iteration.insert(variable.clone(), sequence[index].clone());
+        }
+
+        if depth == 1 {
+            items.push(instantiate(template, &iteration, ellipsis, expansion)?);
+        } else {
+            instantiate_repeated(template, depth - 1, &iteration, ellipsis, expansion, items)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn template_variables(template: &ParseTree, variables: &mut Vec<String>) {
+    match template {
+        ParseTree::Element(Primitive::Identifier(id), _) => variables.push(id.clone()),
+        ParseTree::List(templates, _) => templates.iter().for_each(|template| template_variables(template, variables)),
+        ParseTree::Element(_, _) => {}
+    }
+}
+
+fn rename(id: &str, expansion: &str) -> String {
+    format!("{}{}{}", id, RENAME_MARKER, expansion)
+}
+
+/// Bind `keyword` to the macro described by `specification` in `scope`
+pub fn define_syntax(keyword: &ParseTree, specification: &ParseTree, scope: &Scope) -> Result<String, EvalError> {
+    let name = identifier(keyword)?;
+    let rules = SyntaxRules::parse(specification, scope)?;
+
+    scope.define(name.clone(), Primitive::Syntax(rules));
+    Ok(name)
+}
+
+/// The name an identifier was written as, before any renaming by macro expansion
+pub fn base_name(name: &str) -> &str {
+    match name.find(RENAME_MARKER) {
+        Some(end) => &name[..end],
+        None => name
+    }
+}
+
+/// Look up the variable `name` in `scope`
+///
+/// An identifier renamed by a macro expansion that was not bound under its new name refers to
+/// whatever the name it was renamed from refers to in the scope the macro was defined in.
+pub fn lookup(scope: &Scope, name: &str) -> Option<Primitive> {
+    match scope.get(name) {
+        Some(value) => Some(value),
+        None => {
+            let (original, scope) = unrename(scope, name)?;
+            lookup(&scope, original)
+        }
+    }
+}
+
+/// Assign to the variable `name` in `scope`, resolving renamed identifiers like `lookup` does
+///
+/// Returns false when the variable is not bound.
+pub fn assign(scope: &Scope, name: &str, value: Primitive) -> bool {
+    if scope.set(name, value.clone()) {
+        return true;
+    }
+
+    match unrename(scope, name) {
+        Some((original, scope)) => assign(&scope, original, value),
+        None => false
+    }
+}
+
+/// Undo the last renaming of `name`, returning the name it was renamed from and the scope of the
+/// macro that renamed it, or `scope` if that macro is gone
+fn unrename<'a>(scope: &Scope, name: &'a str) -> Option<(&'a str, Scope)> {
+    let end = name.rfind(RENAME_MARKER)?;
+
+    let definition = name[end..].rsplit(DEFINITION_MARKER).next().and_then(|number| number.parse().ok());
+    let syntax_rules = definition.and_then(|definition| MACROS.with(|macros| macros.borrow().get(&definition).and_then(Weak::upgrade)));
+    let scope = syntax_rules.map_or_else(|| scope.clone(), |syntax_rules| syntax_rules.scope.clone());
+
+    Some((&name[..end], scope))
+}
+
+impl Drop for SyntaxRules {
+    fn drop(&mut self) {
+        let _ = MACROS.try_with(|macros| macros.borrow_mut().remove(&self.definition));
+    }
+}
+
+fn is_ellipsis(tree: &ParseTree, ellipsis: Option<&str>) -> bool {
+    match (tree, ellipsis) {
+        (ParseTree::Element(Primitive::Identifier(id), _), Some(ellipsis)) => id == ellipsis,
+        _ => false
+    }
+}
+
+fn is_keyword(tree: Option<&ParseTree>, keyword: &str) -> bool {
+    matches!(tree, Some(ParseTree::Element(Primitive::Identifier(id), _)) if base_name(id) == keyword)
+}
+
+fn identifier(tree: &ParseTree) -> Result<String, EvalError> {
+    match tree {
+        ParseTree::Element(Primitive::Identifier(id), _) => Ok(id.clone()),
+        _ => Err(EvalError::syntax_error(format!("expected identifier but found {:?}", tree)).with_span(tree.span()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::env;
+    use super::super::error::ErrorKind;
+    use super::super::interpreter::interpret;
+    use super::super::parser::parse;
+    use super::super::types::*;
+
+    #[test]
+    fn expands_simple_macro() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax unless-zero
+              (syntax-rules ()
+                ((_ n body) (if (= n 0) 0 body))))
+            (unless-zero 2 (* 2 21))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(42));
+    }
+
+    #[test]
+    fn introduced_bindings_do_not_capture_user_identifiers() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax my-or
+              (syntax-rules ()
+                ((_) false)
+                ((_ e) e)
+                ((_ e rest ...) ((lambda (tmp) (if tmp tmp (my-or rest ...))) e))))
+            (define tmp 5)
+            (my-or false tmp)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::Integer(5));
+    }
+
+    #[test]
+    fn free_identifiers_refer_to_the_definition_environment() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define x 5)
+            (define-syntax getx (syntax-rules () ((_) x)))
+            (define-syntax setx! (syntax-rules () ((_ v) (set! x v))))
+            (define shadowed (let ((x 99)) (getx)))
+            (let ((x 99)) (setx! 7))
+            (list shadowed x)
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        assert_eq!(result, Primitive::list(vec![Primitive::Integer(5), Primitive::Integer(7)]));
+    }
+
+    #[test]
+    fn ellipsis_patterns() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax sums
+              (syntax-rules ()
+                ((_ (a b ...) ...) (list (+ a b ...) ...))))
+            (sums (1 2) (3 4 5) (6))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![Primitive::Integer(3), Primitive::Integer(12), Primitive::Integer(6)]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn literals_must_match_exactly() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax arrow
+              (syntax-rules (=>)
+                ((_ a => b) (list 'arrow a b))
+                ((_ a b c) (list 'plain a b c))))
+            (list (arrow 1 => 2) (arrow 1 2 3))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![
+            Primitive::list(vec![Primitive::Identifier(String::from("arrow")), Primitive::Integer(1), Primitive::Integer(2)]),
+            Primitive::list(vec![Primitive::Identifier(String::from("plain")), Primitive::Integer(1), Primitive::Integer(2), Primitive::Integer(3)])
+        ]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn let_syntax_is_local() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define result
+              (let-syntax ((double (syntax-rules () ((_ x) (* 2 x)))))
+                (double 21)))
+            (list result (double 1))
+        ").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnboundVariable);
+        assert_eq!(scope.get("result"), Some(Primitive::Integer(42)));
+    }
+
+    #[test]
+    fn escaped_and_custom_ellipsis() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax escaped
+              (syntax-rules ()
+                ((_ x ...) '(x ... (... ...)))))
+            (define-syntax custom
+              (syntax-rules ::: ()
+                ((_ x :::) '(x ::: ...))))
+            (list (escaped 1 2) (custom 1 2))
+        ").unwrap();
+
+        let result = interpret(parse_tree, &scope, true).unwrap();
+        let expected = Primitive::list(vec![Primitive::Integer(1), Primitive::Integer(2), Primitive::Identifier(String::from("..."))]);
+        assert_eq!(result, Primitive::list(vec![expected.clone(), expected]));
+    }
+
+    #[test]
+    fn error_when_no_rule_matches() {
+        let scope = env::standard_env();
+        let parse_tree = parse("
+            (define-syntax pair-only (syntax-rules () ((_ a b) (list a b))))
+            (pair-only 1)
+        ").unwrap();
+
+        let error = interpret(parse_tree, &scope, true).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::SyntaxError);
+        assert_eq!(error.message, "no syntax rule matches this use of pair-only");
+    }
+}
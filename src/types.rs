@@ -1,22 +1,80 @@
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use super::error::{self, EvalError};
+use super::env::Scope;
+use super::interpreter::Context;
+use super::syntax::{self, SyntaxRules};
+
+/// A named piece of source code, shared by every span that points into it
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub text: String
+}
+
+/// The position of an expression in its source, with lines and columns counted from 1
+#[derive(Clone)]
+pub struct Span {
+    pub source: Rc<Source>,
+    pub line: usize,
+    pub column: usize
+}
+
+impl Span {
+    pub fn new(source: &Rc<Source>, line: usize, column: usize) -> Span {
+        Span { source: Rc::clone(source), line, column }
+    }
+
+    /// The line of source code the span starts on
+    pub fn snippet(&self) -> &str {
+        self.source.text.lines().nth(self.line.saturating_sub(1)).unwrap_or("")
+    }
+}
+
+impl Default for Span {
+    fn default() -> Span {
+        let source = Source { name: String::from("<unknown>"), text: String::new() };
+        Span { source: Rc::new(source), line: 1, column: 1 }
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.source, &other.source) && self.line == other.line && self.column == other.column
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source.name, self.line, self.column)
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
 
 #[derive(Clone)]
 pub enum ParseTree {
-    Element(Primitive),
-    List(Vec<ParseTree>)
+    Element(Primitive, Span),
+    List(Vec<ParseTree>, Span)
 }
 
+/// Trees are compared by structure only, so the same code parsed from two places is equal
 impl PartialEq for ParseTree {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (ParseTree::List(vec1), ParseTree::List(vec2)) => vec1 == vec2,
-            (ParseTree::Element(e1), ParseTree::Element(e2)) => e1 == e2,
+            (ParseTree::List(vec1, _), ParseTree::List(vec2, _)) => vec1 == vec2,
+            (ParseTree::Element(e1, _), ParseTree::Element(e2, _)) => e1 == e2,
             _ => false
         }
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone)]
 pub enum Primitive {
     Identifier(String),
     String(String),
@@ -24,8 +82,69 @@ pub enum Primitive {
     Float(f64),
     Bool(bool),
     Nil,
+    Pair(Rc<Primitive>, Rc<Primitive>),
     Function(Function),
-    Lambda(Vec<Primitive>, Box<ParseTree>)
+    Lambda(Parameters, Rc<Vec<ParseTree>>, Scope),
+    /// A `case-lambda` procedure, which runs the first clause accepting the number of arguments
+    CaseLambda(Rc<Vec<(Parameters, Rc<Vec<ParseTree>>)>>, Scope),
+    Syntax(Rc<SyntaxRules>),
+    /// A `defmacro` procedure, called on the unevaluated forms of a macro use to produce its code
+    Macro(Parameters, Rc<Vec<ParseTree>>, Scope)
+}
+
+/// The formal parameters of a procedure, `(required ... #!optional optional ... . rest)`
+///
+/// Optional parameters may have a default expression, evaluated when the argument is missing in
+/// the scope of the parameters before it. Without one they default to `Nil`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Parameters {
+    pub required: Vec<String>,
+    pub optional: Vec<(String, Option<ParseTree>)>,
+    pub rest: Option<String>,
+    /// The name of the procedure taking these parameters, when it was defined with one
+    pub name: Option<String>
+}
+
+impl Parameters {
+    /// Parameters that take exactly the arguments `names`
+    pub fn required(names: Vec<String>) -> Parameters {
+        Parameters { required: names, ..Parameters::default() }
+    }
+
+    /// Whether a call with `count` arguments is valid
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.required.len() && (self.rest.is_some() || count <= self.required.len() + self.optional.len())
+    }
+
+    /// How many arguments are valid, for error messages
+    pub fn arity(&self) -> String {
+        let min = self.required.len();
+        let max = min + self.optional.len();
+
+        match (&self.rest, min == max) {
+            (Some(_), _) => format!("at least {}", error::arguments(min)),
+            (None, true) => error::arguments(min),
+            (None, false) => format!("{} to {}", min, error::arguments(max))
+        }
+    }
+}
+
+impl fmt::Debug for Parameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = self.required.clone();
+
+        if !self.optional.is_empty() {
+            names.push(String::from("#!optional"));
+            names.extend(self.optional.iter().map(|(name, _)| name.clone()));
+        }
+
+        if let Some(rest) = &self.rest {
+            names.push(String::from("."));
+            names.push(rest.clone());
+        }
+
+        write!(f, "({})", names.join(" "))
+    }
 }
 
 impl fmt::Debug for Primitive {
@@ -37,13 +156,165 @@ impl fmt::Debug for Primitive {
             Primitive::Float(i) => write!(f, "Float({})", i),
             Primitive::Bool(i) => write!(f, "Bool({})", i),
             Primitive::Nil => write!(f, "Nil"),
+            Primitive::Pair(car, cdr) => {
+                write!(f, "List[{:?}", car)?;
+
+                let mut rest = cdr;
+                while let Primitive::Pair(car, cdr) = rest.as_ref() {
+                    write!(f, ", {:?}", car)?;
+                    rest = cdr;
+                }
+
+                match rest.as_ref() {
+                    Primitive::Nil => write!(f, "]"),
+                    tail => write!(f, " . {:?}]", tail)
+                }
+            },
             Primitive::Function(_) => write!(f, "Function"),
-            Primitive::Lambda(arguments, _) => write!(f, "Lambda({:?})", arguments)
+            Primitive::Lambda(arguments, _, _) => write!(f, "Lambda({:?})", arguments),
+            Primitive::CaseLambda(clauses, _) => {
+                let arguments = clauses.iter().map(|(arguments, _)| format!("{:?}", arguments)).collect::<Vec<_>>();
+                write!(f, "CaseLambda({})", arguments.join(" "))
+            },
+            Primitive::Syntax(_) => write!(f, "Syntax"),
+            Primitive::Macro(arguments, _, _) => write!(f, "Macro({:?})", arguments)
+        }
+    }
+}
+
+impl PartialEq for Primitive {
+    fn eq(&self, other: &Self) -> bool {
+        let (mut left, mut right) = (self, other);
+
+        // walk along lists rather than recursing into their tails, which long lists would not
+        // have the stack for
+        while let (Primitive::Pair(car1, cdr1), Primitive::Pair(car2, cdr2)) = (left, right) {
+            if car1 != car2 {
+                return false;
+            }
+
+            left = cdr1;
+            right = cdr2;
+        }
+
+        match (left, right) {
+            (Primitive::Identifier(a), Primitive::Identifier(b)) => a == b,
+            (Primitive::String(a), Primitive::String(b)) => a == b,
+            (Primitive::Integer(a), Primitive::Integer(b)) => a == b,
+            (Primitive::Float(a), Primitive::Float(b)) => a == b,
+            (Primitive::Bool(a), Primitive::Bool(b)) => a == b,
+            (Primitive::Nil, Primitive::Nil) => true,
+            (Primitive::Function(a), Primitive::Function(b)) => Rc::ptr_eq(a, b),
+            (Primitive::Lambda(args1, body1, _), Primitive::Lambda(args2, body2, _)) => args1 == args2 && body1 == body2,
+            (Primitive::CaseLambda(a, _), Primitive::CaseLambda(b, _)) => Rc::ptr_eq(a, b),
+            (Primitive::Syntax(a), Primitive::Syntax(b)) => Rc::ptr_eq(a, b),
+            (Primitive::Macro(args1, body1, _), Primitive::Macro(args2, body2, _)) => args1 == args2 && body1 == body2,
+            _ => false
+        }
+    }
+}
+
+impl Primitive {
+    pub fn cons(car: Primitive, cdr: Primitive) -> Primitive {
+        Primitive::Pair(Rc::new(car), Rc::new(cdr))
+    }
+
+    /// Build a proper list, terminated by `Nil`, from `items`
+    pub fn list(items: Vec<Primitive>) -> Primitive {
+        items.into_iter().rev().fold(Primitive::Nil, |tail, item| Primitive::cons(item, tail))
+    }
+}
+
+thread_local! {
+    /// Stands in for the tails of pairs that are being freed
+    static NIL: Rc<Primitive> = Rc::new(Primitive::Nil);
+}
+
+impl Drop for Primitive {
+    /// Free the pairs of a list one after the other, as dropping the tail of each pair inside the
+    /// drop of the pair would recurse once per element
+    fn drop(&mut self) {
+        let mut rest = match self {
+            Primitive::Pair(_, cdr) if Rc::strong_count(cdr) == 1 => unlink(cdr),
+            _ => return
+        };
+
+        while let Ok(mut pair) = Rc::try_unwrap(rest) {
+            rest = match &mut pair {
+                Primitive::Pair(_, cdr) => unlink(cdr),
+                _ => return
+            };
         }
     }
 }
 
-type Function = fn(Vec<Primitive>) -> Primitive;
+/// Detach the tail of a pair, leaving a shared `Nil` in its place
+fn unlink(cdr: &mut Rc<Primitive>) -> Rc<Primitive> {
+    let nil = NIL.try_with(Rc::clone).unwrap_or_else(|_| Rc::new(Primitive::Nil));
+    mem::replace(cdr, nil)
+}
+
+/// A procedure implemented in Rust, called with the context of the call and the evaluated arguments
+pub type Function = Rc<dyn Fn(&Context, Vec<Primitive>) -> Result<Primitive, EvalError>>;
+
+impl ParseTree {
+    pub fn span(&self) -> &Span {
+        match self {
+            ParseTree::Element(_, span) => span,
+            ParseTree::List(_, span) => span
+        }
+    }
+
+    /// Convert source code into the data it denotes, as `(quote ...)` does
+    ///
+    /// Identifiers become symbols, under the name they were written as, and lists become proper
+    /// lists, or improper ones when written as `(a ... . b)`.
+    pub fn into_datum(self) -> Primitive {
+        match self {
+            ParseTree::Element(Primitive::Identifier(ref id), _) => Primitive::Identifier(syntax::base_name(id).to_string()),
+            ParseTree::Element(primitive, _) => primitive,
+            ParseTree::List(list, _) => {
+                let (items, tail) = ParseTree::split_dotted(&list);
+                let tail = tail.map_or(Primitive::Nil, |tail| tail.clone().into_datum());
+
+                items.iter().rev().fold(tail, |tail, item| Primitive::cons(item.clone().into_datum(), tail))
+            }
+        }
+    }
+
+    /// Split the elements of a list written as `(a ... . b)` into `a ...` and `b`
+    pub fn split_dotted(list: &[ParseTree]) -> (&[ParseTree], Option<&ParseTree>) {
+        match list {
+            [items @ .., ParseTree::Element(Primitive::Identifier(dot), _), tail]
+                if !items.is_empty() && syntax::base_name(dot) == "." => (items, Some(tail)),
+            _ => (list, None)
+        }
+    }
+
+    /// Convert data back into source code, the inverse of `into_datum`, giving every node `span`
+    pub fn from_datum(datum: Primitive, span: &Span) -> Result<ParseTree, EvalError> {
+        match datum {
+            Primitive::Nil => Ok(ParseTree::List(vec![], span.clone())),
+            Primitive::Pair(_, _) => {
+                let mut list = vec![];
+                let mut rest = &datum;
+
+                while let Primitive::Pair(car, cdr) = rest {
+                    list.push(ParseTree::from_datum(car.as_ref().clone(), span)?);
+                    rest = cdr;
+                }
+
+                if *rest != Primitive::Nil {
+                    list.push(ParseTree::Element(Primitive::Identifier(String::from(".")), span.clone()));
+                    list.push(ParseTree::from_datum(rest.clone(), span)?);
+                }
+
+                Ok(ParseTree::List(list, span.clone()))
+            },
+            datum => Ok(ParseTree::Element(datum, span.clone()))
+        }
+    }
+}
 
 impl fmt::Debug for ParseTree {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -55,8 +326,8 @@ fn format_tree(tree: &ParseTree, indent: i32) -> String {
     let mut output = String::from("");
 
     match tree {
-        ParseTree::Element(e) => { format!("{:?}", e) },
-        ParseTree::List(vec) => {
+        ParseTree::Element(e, _) => format!("{:?}", e),
+        ParseTree::List(vec, _) => {
             for _ in 0..indent { output.push_str("  "); }
 
             for (pos, ast) in vec.iter().enumerate() {